@@ -0,0 +1,7 @@
+-- Initial schema. IF NOT EXISTS keeps this safe for databases created
+-- before migrations were introduced.
+CREATE TABLE IF NOT EXISTS todos (
+   id INTEGER PRIMARY KEY,
+   title TEXT NOT NULL,
+   completed INTEGER
+);
@@ -0,0 +1,24 @@
+-- Named lists. Existing todos move into the default list.
+CREATE TABLE lists (
+   id INTEGER PRIMARY KEY,
+   slug TEXT NOT NULL UNIQUE,
+   name TEXT NOT NULL
+);
+
+INSERT INTO lists (id, slug, name) VALUES (1, 'default', 'TODO');
+
+-- SQLite can't add a foreign key column in place, so rebuild the table
+CREATE TABLE todos_new (
+   id INTEGER PRIMARY KEY,
+   list_id INTEGER NOT NULL DEFAULT 1 REFERENCES lists(id) ON DELETE CASCADE,
+   title TEXT NOT NULL,
+   completed INTEGER
+);
+
+INSERT INTO todos_new (id, list_id, title, completed)
+SELECT id, 1, title, completed FROM todos;
+
+DROP TABLE todos;
+ALTER TABLE todos_new RENAME TO todos;
+
+CREATE INDEX todos_list_id ON todos (list_id);
@@ -0,0 +1,7 @@
+-- Registered accounts. Passwords are stored as Argon2 PHC strings.
+CREATE TABLE users (
+   id INTEGER PRIMARY KEY,
+   username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+   password_hash TEXT NOT NULL,
+   created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,17 @@
+-- Farcaster identities. Accounts created through Sign In With Farcaster have
+-- no username or password, so those columns become nullable.
+CREATE TABLE users_new (
+   id INTEGER PRIMARY KEY,
+   username TEXT UNIQUE COLLATE NOCASE,
+   password_hash TEXT,
+   fid INTEGER UNIQUE,
+   farcaster_username TEXT,
+   created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+   CHECK ((username IS NOT NULL AND password_hash IS NOT NULL) OR fid IS NOT NULL)
+);
+
+INSERT INTO users_new (id, username, password_hash, created_at)
+SELECT id, username, password_hash, created_at FROM users;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
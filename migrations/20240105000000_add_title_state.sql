@@ -0,0 +1,3 @@
+-- Encoded Yjs document behind each title, so concurrent edits merge instead of clobbering.
+-- NULL until the todo is first edited collaboratively.
+ALTER TABLE todos ADD COLUMN title_state BLOB;
@@ -0,0 +1,2 @@
+-- Bumped on every write, so clients can detect edits based on a stale view
+ALTER TABLE todos ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
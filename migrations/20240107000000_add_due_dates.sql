@@ -0,0 +1,6 @@
+-- Optional deadline and reminder time, as Unix seconds
+ALTER TABLE todos ADD COLUMN due_at INTEGER;
+ALTER TABLE todos ADD COLUMN remind_at INTEGER;
+-- Set once the reminder has gone out, so a restart doesn't send it again
+ALTER TABLE todos ADD COLUMN reminder_sent INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX todos_pending_reminders ON todos (remind_at) WHERE reminder_sent = 0;
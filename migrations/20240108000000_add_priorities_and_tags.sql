@@ -0,0 +1,15 @@
+-- 0 = none, 1 = low, 2 = medium, 3 = high
+ALTER TABLE todos ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE tags (
+   id INTEGER PRIMARY KEY,
+   name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE todo_tags (
+   todo_id INTEGER NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+   tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+   PRIMARY KEY (todo_id, tag_id)
+);
+
+CREATE INDEX todo_tags_tag_id ON todo_tags (tag_id);
@@ -0,0 +1,16 @@
+-- Manual order within a list: fractional ranks from src/rank.rs, compared bytewise
+ALTER TABLE todos ADD COLUMN position TEXT NOT NULL DEFAULT 'a0';
+
+-- Keep the newest-first order existing lists had: 0, 1, 2, ... as integer parts all
+-- as wide as the list needs, with the head letter announcing that width ('a' for 1
+-- digit, 'b' for 2, ...). So a list of 120 todos gets c000 to c119.
+WITH ranked AS (
+   SELECT id,
+          ROW_NUMBER() OVER (PARTITION BY list_id ORDER BY id DESC) - 1 AS n,
+          length(COUNT(*) OVER (PARTITION BY list_id) - 1) AS width
+   FROM todos
+)
+UPDATE todos SET position = char(unicode('a') + ranked.width - 1) || printf('%0*d', ranked.width, ranked.n)
+FROM ranked WHERE ranked.id = todos.id;
+
+CREATE INDEX todos_list_id_position ON todos (list_id, position);
@@ -0,0 +1,3 @@
+-- Subtasks: a todo may belong to another todo of the same list, and goes with it
+ALTER TABLE todos ADD COLUMN parent_id INTEGER REFERENCES todos(id) ON DELETE CASCADE;
+CREATE INDEX todos_parent_id ON todos (parent_id);
@@ -0,0 +1,2 @@
+-- Recurring todos: an RFC 5545 recurrence rule, such as FREQ=WEEKLY;BYDAY=MO
+ALTER TABLE todos ADD COLUMN recurrence TEXT;
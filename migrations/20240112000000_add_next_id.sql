@@ -0,0 +1,3 @@
+-- The occurrence completing a recurring todo added, so completing it again after a
+-- reopen doesn't add another. Deleting that occurrence lets the next completion add one.
+ALTER TABLE todos ADD COLUMN next_id INTEGER REFERENCES todos(id) ON DELETE SET NULL;
@@ -0,0 +1,269 @@
+// Versioned JSON API over the same todos the htmx pages edit. Every write is
+// broadcast like its HTML counterpart, so browsers see API changes live.
+use crate::crdt::TitleDocs;
+use crate::db::{add_todo, delete_todo, get_todo, get_todos, parse_tags, DbError, Priority, Todo, TodoDraft, TodoQuery, User};
+use crate::etag::{IfMatch, Versioned};
+use crate::live::Live;
+use crate::recurrence::Repeat;
+use crate::schedule::When;
+use crate::{publish_add, publish_delete, resolve_list, save_edit, Details, TodoEdit};
+use rocket::http::Status;
+use rocket::response::status::{Created, Custom};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Catcher, Request, Route, State};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+// Where the API is mounted; also the prefix of the Location of new todos
+pub const BASE: &str = "/api/v1";
+
+pub fn routes() -> Vec<Route> {
+    routes![list_todos, get_todo_json, create_todo, patch_todo, delete_todo_json]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![api_error]
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    status: u16,
+    error: String,
+    message: String,
+}
+
+// An error answered with a JSON body instead of the HTML error fragment
+#[derive(Debug)]
+pub struct ApiError(Status, String);
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        println!("Database error: {:?}", e);
+        let message = e.to_string();
+        ApiError(Status::from(e), message)
+    }
+}
+
+impl<'r> rocket::response::Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let ApiError(status, message) = self;
+        Custom(status, Json(error_body(status, message))).respond_to(req)
+    }
+}
+
+fn error_body(status: Status, message: String) -> ErrorBody {
+    ErrorBody {
+        status: status.code,
+        error: status.reason_lossy().to_string(),
+        message,
+    }
+}
+
+// Failed guards and unknown routes under /api answer in JSON too
+#[catch(default)]
+fn api_error(status: Status, req: &Request) -> Json<ErrorBody> {
+    let message = match status.code {
+        401 => "Sign in first".to_string(),
+        404 => format!("No route for {} {}", req.method(), req.uri()),
+        _ => status.reason_lossy().to_string(),
+    };
+    Json(error_body(status, message))
+}
+
+// Dates are RFC 3339, or `YYYY-MM-DD[THH:MM]` in the server's time zone
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewTodo {
+    title: String,
+    // Adds it as a subtask of this todo, in that todo's list
+    parent_id: Option<i64>,
+    due_at: Option<String>,
+    remind_at: Option<String>,
+    priority: Option<Priority>,
+    tags: Option<Vec<String>>,
+    // An RFC 5545 recurrence rule like FREQ=WEEKLY;BYDAY=MO
+    recurrence: Option<String>,
+}
+
+// Fields left out are left alone; an empty due_at, remind_at or recurrence clears it
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TodoPatch {
+    title: Option<String>,
+    completed: Option<bool>,
+    due_at: Option<String>,
+    remind_at: Option<String>,
+    priority: Option<Priority>,
+    // Replaces all of the todo's tags
+    tags: Option<Vec<String>>,
+    recurrence: Option<String>,
+}
+
+fn parse_when(field: Option<&str>) -> Result<When, ApiError> {
+    match field {
+        Some(input) => When::parse(input).map_err(|message| ApiError(Status::UnprocessableEntity, message)),
+        None => Ok(When::Unchanged),
+    }
+}
+
+fn parse_repeat(field: Option<&str>) -> Result<Repeat, ApiError> {
+    match field {
+        Some(input) => Repeat::parse(input).map_err(|message| ApiError(Status::UnprocessableEntity, message)),
+        None => Ok(Repeat::Unchanged),
+    }
+}
+
+fn require_title(title: &str) -> Result<(), ApiError> {
+    if title.trim().is_empty() {
+        return Err(ApiError(Status::UnprocessableEntity, "Title must not be empty".to_string()));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "todos",
+    params(
+        ("list" = Option<String>, Query, description = "List slug; the default list when left out"),
+        TodoQuery,
+    ),
+    responses(
+        (status = 200, description = "Todos of the list that match the query", body = Vec<Todo>),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "No such list", body = ErrorBody),
+    ),
+    security(("session" = []))
+)]
+#[get("/todos?<list>&<query..>")]
+async fn list_todos(_user: User, pool: &State<SqlitePool>, list: Option<&str>, query: TodoQuery) -> Result<Json<Vec<Todo>>, ApiError> {
+    let list = resolve_list(pool, list).await?;
+    Ok(Json(get_todos(pool, list.id, &query).await?))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "todos",
+    params(("id" = i64, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "The todo, with its version as the ETag", body = Todo,
+            headers(("ETag" = String, description = "Quoted version, to send back as If-Match"))),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "No such todo", body = ErrorBody),
+    ),
+    security(("session" = []))
+)]
+#[get("/todos/<id>")]
+async fn get_todo_json(_user: User, pool: &State<SqlitePool>, id: i64) -> Result<Versioned<Json<Todo>>, ApiError> {
+    let todo = get_todo(pool, id).await?;
+    let version = todo.version;
+    Ok(Versioned::new(Json(todo), version))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "todos",
+    params(("list" = Option<String>, Query, description = "List slug; the default list when left out")),
+    request_body = NewTodo,
+    responses(
+        (status = 201, description = "Created; broadcast as `add`", body = Todo,
+            headers(("Location" = String, description = "URL of the new todo"))),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "No such list or parent todo", body = ErrorBody),
+        (status = 422, description = "Missing or empty title, a date or recurrence rule that can't be read, or a parent already as deep as subtasks go", body = ErrorBody),
+    ),
+    security(("session" = []))
+)]
+#[post("/todos?<list>", format = "json", data = "<body>")]
+async fn create_todo(user: User, pool: &State<SqlitePool>, list: Option<&str>, body: Json<NewTodo>, live: Live<'_>) -> Result<Created<Json<Todo>>, ApiError> {
+    require_title(&body.title)?;
+    let due_at = parse_when(body.due_at.as_deref())?.apply(None);
+    let remind_at = parse_when(body.remind_at.as_deref())?.apply(None);
+    let recurrence = parse_repeat(body.recurrence.as_deref())?.apply(None);
+    let list_id = match body.parent_id {
+        Some(parent_id) => get_todo(pool, parent_id).await?.list_id,
+        None => resolve_list(pool, list).await?.id,
+    };
+    let tags = parse_tags(&body.tags.clone().unwrap_or_default().join(","));
+    let draft = TodoDraft {
+        title: &body.title,
+        parent_id: body.parent_id,
+        due_at,
+        remind_at,
+        priority: body.priority.unwrap_or_default(),
+        tags: &tags,
+        recurrence: recurrence.as_deref(),
+    };
+    let id = add_todo(pool, list_id, &draft).await?;
+    let todo = get_todo(pool, id).await?;
+
+    publish_add(&live, pool, &todo, user.source_id()).await;
+    Ok(Created::new(format!("{}/todos/{}", BASE, id)).body(Json(todo)))
+}
+
+// Change the title and/or completed state. With If-Match, fails with 412
+// unless the todo is still at that version.
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "todos",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the change is based on"),
+    ),
+    request_body = TodoPatch,
+    responses(
+        (status = 200, description = "Updated; broadcast as `update` and/or `toggle`, the latter also for todos completed or reopened with it, \
+            and the next occurrence of a recurring todo as `add`", body = Todo,
+            headers(("ETag" = String, description = "Quoted new version"))),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "No such todo", body = ErrorBody),
+        (status = 412, description = "The todo is no longer at the If-Match version, or If-Match is not a version this server issued", body = ErrorBody),
+        (status = 422, description = "Empty title, or a date or recurrence rule that can't be read", body = ErrorBody),
+    ),
+    security(("session" = []))
+)]
+#[patch("/todos/<id>", format = "json", data = "<body>")]
+async fn patch_todo(user: User, pool: &State<SqlitePool>, titles: &State<TitleDocs>, id: i64, if_match: IfMatch, body: Json<TodoPatch>, live: Live<'_>) -> Result<Versioned<Json<Todo>>, ApiError> {
+    // Everything is checked before anything is written
+    if let Some(title) = body.title.as_deref() {
+        require_title(title)?;
+    }
+    let details = Details {
+        due_at: parse_when(body.due_at.as_deref())?,
+        remind_at: parse_when(body.remind_at.as_deref())?,
+        priority: body.priority,
+        tags: body.tags.as_ref().map(|tags| parse_tags(&tags.join(","))),
+        recurrence: parse_repeat(body.recurrence.as_deref())?,
+    };
+    let edit = TodoEdit {
+        title: body.title.clone(),
+        completed: body.completed,
+        details,
+    };
+    let todo = get_todo(pool, id).await?;
+    let todo = save_edit(&live, pool, titles, todo, edit, if_match.0, user.source_id()).await?;
+
+    let version = todo.version;
+    Ok(Versioned::new(Json(todo), version))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    tag = "todos",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the delete is based on"),
+    ),
+    responses(
+        (status = 204, description = "Deleted along with its subtasks; broadcast as a `delete` for each"),
+        (status = 401, description = "Not signed in", body = ErrorBody),
+        (status = 404, description = "No such todo", body = ErrorBody),
+        (status = 412, description = "The todo is no longer at the If-Match version", body = ErrorBody),
+    ),
+    security(("session" = []))
+)]
+#[delete("/todos/<id>")]
+async fn delete_todo_json(user: User, pool: &State<SqlitePool>, titles: &State<TitleDocs>, id: i64, if_match: IfMatch, live: Live<'_>) -> Result<Status, ApiError> {
+    let todo = get_todo(pool, id).await?;
+    let subtasks = delete_todo(pool, id, if_match.0).await?;
+    publish_delete(&live, pool, titles, &todo, &subtasks, user.source_id()).await;
+    Ok(Status::NoContent)
+}
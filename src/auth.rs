@@ -0,0 +1,302 @@
+use crate::db::{add_farcaster_user, add_user, get_user, get_user_by_fid, get_user_login, link_farcaster, DbError, User};
+use crate::farcaster::{verify_sign_in, FidResolver, SiwfError};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rocket::form::Form;
+use rocket::http::uri::Host;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FlashMessage, FromRequest, Outcome};
+use rocket::response::status::Custom;
+use rocket::response::{Flash, Redirect};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rand::Rng;
+use rocket::tokio::task::spawn_blocking;
+use rocket::{Request, Route, State};
+use rocket_dyn_templates::{context, Template};
+use sqlx::SqlitePool;
+
+// Private (encrypted) cookie holding the signed-in user's id
+const SESSION_COOKIE: &str = "session";
+// Single-use nonce a Farcaster sign-in message has to carry
+const NONCE_COOKIE: &str = "siwf_nonce";
+// Checked in place of a real hash when the username doesn't exist, so signing in as
+// an unknown user takes as long as a wrong password. Argon2's default parameters, of
+// a random password nobody knows.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$k0QwOVbHkptk7ZDY3dMcHg$ZOvaIvfXzluucXalT1FpajoS2XwJjx9qE8jt8ivMZ2I";
+
+pub fn routes() -> Vec<Route> {
+    routes![get_login, post_login, post_register, post_logout, get_farcaster_nonce, post_farcaster]
+}
+
+impl User {
+    // Identity attached to every broadcast this user causes
+    pub fn source_id(&self) -> String {
+        format!("user_{}", self.id)
+    }
+
+    // Name shown to other people, preferring the Farcaster identity
+    pub fn display_name(&self) -> String {
+        match (&self.farcaster_username, &self.username, self.fid) {
+            (Some(fname), _, _) => format!("@{}", fname),
+            (None, Some(username), _) => username.clone(),
+            (None, None, Some(fid)) => format!("fid:{}", fid),
+            (None, None, None) => self.source_id(),
+        }
+    }
+}
+
+// Requires a signed-in user; forwards with 401 otherwise
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = DbError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_id = req
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| cookie.value().parse::<i64>().ok());
+        let Some(user_id) = user_id else {
+            return Outcome::Forward(Status::Unauthorized);
+        };
+
+        let pool = match req.guard::<&State<SqlitePool>>().await {
+            Outcome::Success(pool) => pool,
+            _ => return Outcome::Forward(Status::InternalServerError),
+        };
+        match get_user(pool, user_id).await {
+            Ok(user) => Outcome::Success(user),
+            // The account is gone, so the cookie is worthless
+            Err(DbError::NotFound(_)) => {
+                req.cookies().remove_private(SESSION_COOKIE);
+                Outcome::Forward(Status::Unauthorized)
+            }
+            Err(e) => Outcome::Error((Status::InternalServerError, e)),
+        }
+    }
+}
+
+// SameSite=None so cookies survive inside the Farcaster frame iframe; the SameOrigin
+// fairing (csrf.rs) keeps other sites from riding on them
+fn frame_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::None)
+        .build()
+}
+
+fn start_session(cookies: &CookieJar<'_>, user: &User) {
+    cookies.add_private(frame_cookie(SESSION_COOKIE, user.id.to_string()));
+}
+
+// Argon2 is deliberately slow, so keep it off the async workers
+async fn hash_password(password: String) -> Option<String> {
+    spawn_blocking(move || {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).ok()?;
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .ok()
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+async fn verify_password(password: String, hash: String) -> bool {
+    spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[derive(FromForm, utoipa::ToSchema)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[get("/login")]
+fn get_login(flash: Option<FlashMessage<'_>>) -> Template {
+    Template::render(
+        "login",
+        context! {
+            error: flash.map(|f| f.message().to_string())
+        },
+    )
+}
+
+#[utoipa::path(
+    tag = "auth",
+    request_body(content = Credentials, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to / with the session cookie set, or back to /login with an error to show"),
+    )
+)]
+#[post("/login", data = "<form>")]
+async fn post_login(cookies: &CookieJar<'_>, pool: &State<SqlitePool>, form: Form<Credentials>) -> Result<Redirect, Flash<Redirect>> {
+    let failed = || Flash::error(Redirect::to(uri!(get_login)), "Wrong username or password");
+
+    let login = get_user_login(pool, form.username.trim()).await;
+    let hash = match &login {
+        Ok((_, hash)) => hash.clone(),
+        Err(_) => DUMMY_HASH.to_string(),
+    };
+    let verified = verify_password(form.password.clone(), hash).await;
+    let user = match login {
+        Ok((user, _)) if verified => user,
+        _ => return Err(failed()),
+    };
+
+    println!("User {} signed in", user.display_name());
+    start_session(cookies, &user);
+    Ok(Redirect::to("/"))
+}
+
+#[utoipa::path(
+    tag = "auth",
+    request_body(content = Credentials, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to / signed in as the new account, or back to /login with why it couldn't be created"),
+    )
+)]
+#[post("/register", data = "<form>")]
+async fn post_register(cookies: &CookieJar<'_>, pool: &State<SqlitePool>, form: Form<Credentials>) -> Result<Redirect, Flash<Redirect>> {
+    let fail = |message: &str| Flash::error(Redirect::to(uri!(get_login)), message);
+
+    let username = form.username.trim();
+    let valid_username = (3..=32).contains(&username.len())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_username {
+        return Err(fail("Usernames are 3-32 letters, digits, '_' or '-'"));
+    }
+    if form.password.len() < 8 {
+        return Err(fail("Passwords need at least 8 characters"));
+    }
+
+    let hash = hash_password(form.password.clone())
+        .await
+        .ok_or_else(|| fail("Could not create account"))?;
+    let id = match add_user(pool, username, &hash).await {
+        Ok(id) => id,
+        Err(DbError::Conflict(_)) => return Err(fail("That username is taken")),
+        Err(_) => return Err(fail("Could not create account")),
+    };
+
+    let user = User {
+        id,
+        username: Some(username.to_string()),
+        fid: None,
+        farcaster_username: None,
+    };
+    println!("✅ Registered user {}", user.display_name());
+    start_session(cookies, &user);
+    Ok(Redirect::to("/"))
+}
+
+#[utoipa::path(
+    tag = "auth",
+    responses((status = 303, description = "Clears the session cookie and redirects to /login"))
+)]
+#[post("/logout")]
+fn post_logout(cookies: &CookieJar<'_>) -> Redirect {
+    cookies.remove_private(SESSION_COOKIE);
+    Redirect::to(uri!(get_login))
+}
+
+// Nonce for the next Sign In With Farcaster message
+#[utoipa::path(
+    tag = "auth",
+    responses((status = 200, description = "Nonce to put in the message; also kept in a cookie until it's used", content_type = "text/plain", body = String))
+)]
+#[get("/auth/farcaster/nonce")]
+fn get_farcaster_nonce(cookies: &CookieJar<'_>) -> String {
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    cookies.add_private(frame_cookie(NONCE_COOKIE, nonce.clone()));
+    nonce
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(crate = "rocket::serde")]
+struct FarcasterSignIn {
+    // EIP-4361 message for this host with the nonce from /auth/farcaster/nonce
+    message: String,
+    // Hex signature by the FID's custody address, with or without 0x
+    signature: String,
+}
+
+// Verify a SIWF signature, then sign in (or link the current account) by FID
+#[utoipa::path(
+    tag = "auth",
+    request_body = FarcasterSignIn,
+    responses(
+        (status = 200, description = "Signed in, with the session cookie set", body = User),
+        (status = 401, description = "No nonce requested, or the message or signature doesn't check out", content_type = "text/plain", body = String),
+        (status = 503, description = "The Farcaster hub couldn't be asked who holds the FID", content_type = "text/plain", body = String),
+    )
+)]
+#[post("/auth/farcaster", format = "json", data = "<sign_in>")]
+async fn post_farcaster(
+    cookies: &CookieJar<'_>,
+    host: &Host<'_>,
+    pool: &State<SqlitePool>,
+    resolver: &State<Box<dyn FidResolver>>,
+    current: Option<User>,
+    sign_in: Json<FarcasterSignIn>,
+) -> Result<Json<User>, Custom<String>> {
+    let db_error = |e: DbError| {
+        let message = e.to_string();
+        Custom(Status::from(e), message)
+    };
+
+    let nonce = cookies
+        .get_private(NONCE_COOKIE)
+        .ok_or_else(|| Custom(Status::Unauthorized, "Request a nonce first".to_string()))?;
+    cookies.remove_private(NONCE_COOKIE);
+
+    let verified = verify_sign_in(
+        &sign_in.message,
+        &sign_in.signature,
+        &host.to_string(),
+        nonce.value(),
+        chrono::Utc::now(),
+        resolver.as_ref(),
+    )
+    .await
+    .map_err(|e| match e {
+        SiwfError::Unresolved(_) => Custom(Status::ServiceUnavailable, e.to_string()),
+        e => Custom(Status::Unauthorized, e.to_string()),
+    })?;
+
+    // From the hub, since anything the client says about its name is unsigned. A name
+    // that can't be looked up right now leaves the stored one as it is.
+    let fname = resolver.fname(verified.fid).await.unwrap_or_else(|e| {
+        println!("Failed to look up the fname of fid {}: {}", verified.fid, e);
+        None
+    });
+    let fname = fname.as_deref();
+
+    let user_id = match current {
+        // A signed-in account without a FID gets this one attached
+        Some(user) if user.fid.is_none() => user.id,
+        _ => match get_user_by_fid(pool, verified.fid).await {
+            Ok(user) => user.id,
+            Err(DbError::NotFound(_)) => add_farcaster_user(pool, verified.fid, fname).await.map_err(db_error)?,
+            Err(e) => return Err(db_error(e)),
+        },
+    };
+    link_farcaster(pool, user_id, verified.fid, fname).await.map_err(db_error)?;
+    let user = get_user(pool, user_id).await.map_err(db_error)?;
+
+    println!("User {} signed in with Farcaster fid {}", user.display_name(), verified.fid);
+    start_session(cookies, &user);
+    Ok(Json(user))
+}
@@ -0,0 +1,117 @@
+use crate::protocol::{ServerMessage, TodoUpdate};
+use rocket::tokio::sync::broadcast::{channel, Receiver, Sender};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// Numbered broadcast channel that remembers recent events for replay
+pub struct Broadcaster {
+    sender: Sender<TodoUpdate>,
+    history: Mutex<History>,
+    history_capacity: usize,
+    // Changes on every restart, so sequence numbers from an old process are never trusted
+    epoch: u32,
+}
+
+struct History {
+    seq: u64,
+    events: VecDeque<TodoUpdate>,
+    // Highest seq that was pushed out of the ring buffer
+    evicted_through: u64,
+}
+
+impl Broadcaster {
+    pub fn new(channel_capacity: usize, history_capacity: usize) -> Self {
+        Broadcaster {
+            sender: channel(channel_capacity).0,
+            history: Mutex::new(History {
+                seq: 0,
+                events: VecDeque::with_capacity(history_capacity),
+                evicted_through: 0,
+            }),
+            history_capacity,
+            epoch: rand::random(),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<TodoUpdate> {
+        self.sender.subscribe()
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    // Sequence number of the latest published event
+    pub fn current_seq(&self) -> u64 {
+        self.history.lock().unwrap().seq
+    }
+
+    // Number, remember and send an event; returns its sequence number
+    pub fn publish(&self, message: ServerMessage, source_id: Option<String>) -> u64 {
+        // Hold the lock while sending so channel order always matches seq order
+        let mut history = self.history.lock().unwrap();
+        history.seq += 1;
+        let update = TodoUpdate {
+            seq: history.seq,
+            message,
+            source_id,
+        };
+
+        if update.message.is_replayable() {
+            if history.events.len() == self.history_capacity {
+                if let Some(evicted) = history.events.pop_front() {
+                    history.evicted_through = evicted.seq;
+                }
+            }
+            history.events.push_back(update.clone());
+        }
+
+        // No receivers is fine, the event is still kept for replay
+        let _ = self.sender.send(update);
+        history.seq
+    }
+
+    // Events after `last_seq`, or None if they can no longer be replayed
+    pub fn replay_since(&self, epoch: u32, last_seq: u64) -> Option<Vec<TodoUpdate>> {
+        let history = self.history.lock().unwrap();
+        if epoch != self.epoch || last_seq < history.evicted_through || last_seq > history.seq {
+            return None;
+        }
+        Some(
+            history
+                .events
+                .iter()
+                .filter(|update| update.seq > last_seq)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+// One broadcaster per list, so each list is its own WebSocket room. Clones share
+// the same rooms.
+#[derive(Clone)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<i64, Arc<Broadcaster>>>>,
+    channel_capacity: usize,
+    history_capacity: usize,
+}
+
+impl Rooms {
+    pub fn new(channel_capacity: usize, history_capacity: usize) -> Self {
+        Rooms {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            channel_capacity,
+            history_capacity,
+        }
+    }
+
+    // The broadcaster for a list, created on first use
+    pub fn get(&self, list_id: i64) -> Arc<Broadcaster> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(list_id)
+            .or_insert_with(|| Arc::new(Broadcaster::new(self.channel_capacity, self.history_capacity)))
+            .clone()
+    }
+}
@@ -0,0 +1,9 @@
+use std::str::FromStr;
+
+// A setting from the environment, or the default when it's unset or doesn't parse
+pub fn var<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
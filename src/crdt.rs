@@ -0,0 +1,470 @@
+// Collaborative title editing: every todo title is a Yjs-compatible text document,
+// so concurrent edits merge instead of the last save winning.
+use crate::db::{edit_todo, get_todo_title_state, init_todo_title_state, save_todo_title, Completion, DbError, TodoChanges};
+use rocket::tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard, OnceCell};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, GetString, OffsetKind, Options, ReadTxn, StateVector, Text, TextRef, Transact, Update};
+
+// Name of the shared text inside each document; the browser has to use the same one
+const TITLE: &str = "title";
+
+pub struct TitleDoc {
+    doc: Doc,
+    text: TextRef,
+}
+
+impl TitleDoc {
+    fn empty() -> Self {
+        // Yjs indexes text in UTF-16 code units, so the server has to as well
+        let doc = Doc::with_options(Options {
+            offset_kind: OffsetKind::Utf16,
+            ..Options::default()
+        });
+        let text = doc.get_or_insert_text(TITLE);
+        TitleDoc { doc, text }
+    }
+
+    // A new document holding a plain title
+    pub fn new(title: &str) -> Self {
+        let title_doc = TitleDoc::empty();
+        title_doc.text.insert(&mut title_doc.doc.transact_mut(), 0, title);
+        title_doc
+    }
+
+    // Load a document saved with `state`
+    pub fn from_state(state: &[u8]) -> Result<Self, TitleError> {
+        let title_doc = TitleDoc::empty();
+        title_doc.apply(state)?;
+        Ok(title_doc)
+    }
+
+    // Merge an encoded update from a client
+    pub fn apply(&self, update: &[u8]) -> Result<(), TitleError> {
+        let update = Update::decode_v1(update).map_err(|e| TitleError::InvalidUpdate(e.to_string()))?;
+        self.doc
+            .transact_mut()
+            .apply_update(update)
+            .map_err(|e| TitleError::InvalidUpdate(e.to_string()))
+    }
+
+    // Overwrite the whole title, returning the update that does it
+    pub fn replace(&self, title: &str) -> Vec<u8> {
+        let before = self.doc.transact().state_vector();
+        {
+            let mut txn = self.doc.transact_mut();
+            let len = self.text.len(&txn);
+            self.text.remove_range(&mut txn, 0, len);
+            self.text.insert(&mut txn, 0, title);
+        }
+        self.doc.transact().encode_state_as_update_v1(&before)
+    }
+
+    pub fn title(&self) -> String {
+        self.text.get_string(&self.doc.transact())
+    }
+
+    // Everything needed to rebuild the document, for saving or for a client opening it
+    pub fn state(&self) -> Vec<u8> {
+        self.doc.transact().encode_state_as_update_v1(&StateVector::default())
+    }
+
+    async fn save(&self, pool: &SqlitePool, todo_id: i64) -> Result<String, DbError> {
+        let title = self.title();
+        save_todo_title(pool, todo_id, &title, &self.state()).await?;
+        Ok(title)
+    }
+}
+
+// A todo's document, loaded by whichever operation needs it first
+type Slot = Arc<OnceCell<AsyncMutex<TitleDoc>>>;
+
+#[derive(Default)]
+struct Entry {
+    doc: Slot,
+    // Connections that have the todo open in their editor
+    editors: HashSet<String>,
+    // Typing has been saved since the todo's version last changed
+    typed: bool,
+}
+
+// Title documents of todos being edited. Each is loaded on first use and dropped
+// again once no editor has it open and no write is using it.
+#[derive(Default)]
+pub struct TitleDocs {
+    // Never held across an await: loading a document only holds up that todo
+    entries: Mutex<HashMap<i64, Entry>>,
+}
+
+// One operation's hold on a todo's document, which lets it go when dropped
+struct Lease<'a> {
+    docs: &'a TitleDocs,
+    todo_id: i64,
+    slot: Slot,
+}
+
+impl Lease<'_> {
+    async fn lock(&self, pool: &SqlitePool) -> Result<AsyncMutexGuard<'_, TitleDoc>, DbError> {
+        let doc = self.slot.get_or_try_init(|| load(pool, self.todo_id)).await?;
+        Ok(doc.lock().await)
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        drop(std::mem::take(&mut self.slot));
+        self.docs.release(self.todo_id);
+    }
+}
+
+async fn load(pool: &SqlitePool, todo_id: i64) -> Result<AsyncMutex<TitleDoc>, DbError> {
+    let doc = match get_todo_title_state(pool, todo_id).await? {
+        (_, Some(state)) => TitleDoc::from_state(&state).map_err(corrupt)?,
+        (title, None) => {
+            // Save straight away; a document rebuilt later would not merge with this one
+            let doc = TitleDoc::new(&title);
+            init_todo_title_state(pool, todo_id, &doc.state()).await?;
+            doc
+        }
+    };
+    Ok(AsyncMutex::new(doc))
+}
+
+impl TitleDocs {
+    fn entries(&self) -> MutexGuard<'_, HashMap<i64, Entry>> {
+        self.entries.lock().unwrap()
+    }
+
+    fn lease(&self, todo_id: i64) -> Lease<'_> {
+        let slot = self.entries().entry(todo_id).or_default().doc.clone();
+        Lease { docs: self, todo_id, slot }
+    }
+
+    // Drop a document nobody has open or is writing through
+    fn release(&self, todo_id: i64) {
+        let mut entries = self.entries();
+        let idle = entries
+            .get(&todo_id)
+            .is_some_and(|entry| entry.editors.is_empty() && Arc::strong_count(&entry.doc) == 1);
+        if idle {
+            entries.remove(&todo_id);
+        }
+    }
+
+    // A connection entered edit mode on the todo; keeps its document loaded. Returns
+    // whether it didn't have it open already.
+    pub fn open(&self, todo_id: i64, connection_id: &str) -> bool {
+        self.entries().entry(todo_id).or_default().editors.insert(connection_id.to_string())
+    }
+
+    // The connection left edit mode, switched todos or went away
+    pub fn close(&self, todo_id: i64, connection_id: &str) {
+        if let Some(entry) = self.entries().get_mut(&todo_id) {
+            entry.editors.remove(connection_id);
+        }
+        self.release(todo_id);
+    }
+
+    // The connection went away, taking whatever it had open with it
+    pub fn leave(&self, connection_id: &str) {
+        let mut entries = self.entries();
+        for entry in entries.values_mut() {
+            entry.editors.remove(connection_id);
+        }
+        entries.retain(|_, entry| !entry.editors.is_empty() || Arc::strong_count(&entry.doc) > 1);
+    }
+
+    // Merge and save a client's update, returning the merged title
+    pub async fn apply(&self, pool: &SqlitePool, todo_id: i64, update: &[u8]) -> Result<String, TitleError> {
+        let lease = self.lease(todo_id);
+        let doc = lease.lock(pool).await?;
+
+        // Work on a copy so a failed save leaves the live document as the database has it
+        let draft = TitleDoc::from_state(&doc.state()).map_err(corrupt)?;
+        draft.apply(update)?;
+        let title = draft.save(pool, todo_id).await?;
+        doc.apply(update)?;
+        self.set_typed(todo_id, true);
+        Ok(title)
+    }
+
+    // An editor saved: typing since the last version gets a new one, for the
+    // Update that announces it. Returns whether there was any. Typing nobody saves
+    // before its document is dropped keeps the version it started from.
+    pub async fn commit(&self, pool: &SqlitePool, todo_id: i64) -> Result<bool, DbError> {
+        if !self.set_typed(todo_id, false) {
+            return Ok(false);
+        }
+        if let Err(e) = edit_todo(pool, todo_id, &TodoChanges::default(), None).await {
+            self.set_typed(todo_id, true);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    // Returns what the flag was
+    fn set_typed(&self, todo_id: i64, typed: bool) -> bool {
+        match self.entries().get_mut(&todo_id) {
+            Some(entry) => std::mem::replace(&mut entry.typed, typed),
+            None => false,
+        }
+    }
+
+    // Set the title outside the editor, written together with any other changes.
+    // Returns the update live editors need. With an expected version, fails with
+    // VersionMismatch if the todo changed since.
+    pub async fn replace(&self, pool: &SqlitePool, todo_id: i64, title: &str, changes: TodoChanges, expected_version: Option<i64>) -> Result<(Vec<u8>, Completion), DbError> {
+        let lease = self.lease(todo_id);
+        let doc = lease.lock(pool).await?;
+
+        // Work on a copy so a rejected write leaves the live document untouched
+        let draft = TitleDoc::from_state(&doc.state()).map_err(corrupt)?;
+        let update = draft.replace(title);
+        let changes = TodoChanges {
+            title: Some((draft.title(), draft.state())),
+            ..changes
+        };
+        let completion = edit_todo(pool, todo_id, &changes, expected_version).await?;
+        doc.apply(&update).map_err(corrupt)?;
+        self.set_typed(todo_id, false);
+        Ok((update, completion))
+    }
+
+    pub async fn state(&self, pool: &SqlitePool, todo_id: i64) -> Result<Vec<u8>, DbError> {
+        let lease = self.lease(todo_id);
+        let state = lease.lock(pool).await?.state();
+        Ok(state)
+    }
+
+    pub fn forget(&self, todo_id: i64) {
+        self.entries().remove(&todo_id);
+    }
+
+    // Documents currently loaded
+    #[cfg(test)]
+    fn loaded(&self) -> usize {
+        self.entries().len()
+    }
+}
+
+// A saved document that no longer decodes is a storage problem, not the client's
+fn corrupt(e: TitleError) -> DbError {
+    DbError::Other(sqlx::Error::Decode(Box::new(e)))
+}
+
+#[derive(Debug)]
+pub enum TitleError {
+    Db(DbError),
+    InvalidUpdate(String),
+}
+
+impl std::fmt::Display for TitleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TitleError::Db(e) => write!(f, "{}", e),
+            TitleError::InvalidUpdate(e) => write!(f, "Invalid title update: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TitleError {}
+
+impl From<DbError> for TitleError {
+    fn from(e: DbError) -> Self {
+        TitleError::Db(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_todo, get_todo, test_pool, TodoChanges, TodoDraft};
+
+    // A browser editing the title, with its own replica
+    struct Client(TitleDoc);
+
+    impl Client {
+        fn open(server: &TitleDoc) -> Self {
+            Client(TitleDoc::from_state(&server.state()).unwrap())
+        }
+
+        // Edit locally, returning the update the browser would send
+        fn edit(&self, index: u32, remove: u32, insert: &str) -> Vec<u8> {
+            let before = self.0.doc.transact().state_vector();
+            {
+                let mut txn = self.0.doc.transact_mut();
+                self.0.text.remove_range(&mut txn, index, remove);
+                self.0.text.insert(&mut txn, index, insert);
+            }
+            self.0.doc.transact().encode_state_as_update_v1(&before)
+        }
+    }
+
+    fn assert_converged(server: &TitleDoc, clients: &[&Client]) -> String {
+        let title = server.title();
+        for client in clients {
+            assert_eq!(client.0.title(), title);
+        }
+        title
+    }
+
+    #[test]
+    fn concurrent_inserts_at_same_position_converge() {
+        let server = TitleDoc::new("buy milk");
+        let alice = Client::open(&server);
+        let bob = Client::open(&server);
+
+        let a = alice.edit(8, 0, " and eggs");
+        let b = bob.edit(8, 0, " today");
+
+        // The server and each client see the two edits in different orders
+        server.apply(&b).unwrap();
+        server.apply(&a).unwrap();
+        alice.0.apply(&b).unwrap();
+        bob.0.apply(&a).unwrap();
+
+        let title = assert_converged(&server, &[&alice, &bob]);
+        assert!(title.starts_with("buy milk"));
+        assert!(title.contains(" and eggs") && title.contains(" today"));
+    }
+
+    #[test]
+    fn interleaved_keystrokes_converge() {
+        let server = TitleDoc::new("call mom");
+        let alice = Client::open(&server);
+        let bob = Client::open(&server);
+
+        // Alice types at the start while Bob deletes and retypes the end, one key at a time
+        let mut from_alice = Vec::new();
+        let mut from_bob = Vec::new();
+        for (i, c) in "please ".chars().enumerate() {
+            from_alice.push(alice.edit(i as u32, 0, &c.to_string()));
+        }
+        from_bob.push(bob.edit(5, 3, ""));
+        for (i, c) in "dad".chars().enumerate() {
+            from_bob.push(bob.edit(5 + i as u32, 0, &c.to_string()));
+        }
+
+        // Deliver alternately, as the server would receive them off two sockets
+        let mut alice_updates = from_alice.iter();
+        let mut bob_updates = from_bob.iter();
+        loop {
+            let (a, b) = (alice_updates.next(), bob_updates.next());
+            if a.is_none() && b.is_none() {
+                break;
+            }
+            for update in a.into_iter().chain(b) {
+                server.apply(update).unwrap();
+            }
+        }
+        for update in &from_bob {
+            alice.0.apply(update).unwrap();
+        }
+        for update in &from_alice {
+            bob.0.apply(update).unwrap();
+        }
+
+        assert_eq!(assert_converged(&server, &[&alice, &bob]), "please call dad");
+    }
+
+    #[test]
+    fn concurrent_delete_and_insert_converge() {
+        let server = TitleDoc::new("water the plants");
+        let alice = Client::open(&server);
+        let bob = Client::open(&server);
+
+        // Alice deletes "the " while Bob inserts inside it
+        let a = alice.edit(6, 4, "");
+        let b = bob.edit(8, 0, "X");
+
+        server.apply(&a).unwrap();
+        server.apply(&b).unwrap();
+        alice.0.apply(&b).unwrap();
+        bob.0.apply(&a).unwrap();
+
+        assert_eq!(assert_converged(&server, &[&alice, &bob]), "water Xplants");
+    }
+
+    #[test]
+    fn server_replace_merges_with_client_edits() {
+        let server = TitleDoc::new("draft");
+        let alice = Client::open(&server);
+
+        let from_alice = alice.edit(5, 0, "!");
+        let from_server = server.replace("final");
+        server.apply(&from_alice).unwrap();
+        alice.0.apply(&from_server).unwrap();
+
+        assert_converged(&server, &[&alice]);
+    }
+
+    #[test]
+    fn utf16_offsets_match_the_browser() {
+        let server = TitleDoc::new("🥛 milk");
+        let alice = Client::open(&server);
+        // The emoji is two UTF-16 units, as String.length counts it in JavaScript
+        server.apply(&alice.edit(2, 0, " oat")).unwrap();
+        assert_eq!(server.title(), "🥛 oat milk");
+    }
+
+    #[test]
+    fn saved_state_reloads_and_rejects_garbage() {
+        let server = TitleDoc::new("walk dog");
+        let alice = Client::open(&server);
+        server.apply(&alice.edit(0, 0, "⏰ ")).unwrap();
+
+        let reloaded = TitleDoc::from_state(&server.state()).unwrap();
+        assert_eq!(reloaded.title(), "⏰ walk dog");
+        assert!(reloaded.apply(&[0xff, 0x00, 0x13]).is_err());
+    }
+
+    async fn todo(pool: &SqlitePool, title: &str) -> i64 {
+        add_todo(pool, 1, &TodoDraft { title, ..Default::default() }).await.unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn docs_stay_loaded_while_someone_edits() {
+        let pool = test_pool().await;
+        let docs = TitleDocs::default();
+        let id = todo(&pool, "buy milk").await;
+
+        // A write from outside the editor doesn't keep the document around
+        docs.replace(&pool, id, "buy oat milk", TodoChanges::default(), None).await.unwrap();
+        assert_eq!(docs.loaded(), 0);
+
+        docs.open(id, "alice");
+        docs.open(id, "bob");
+        let alice = Client(TitleDoc::from_state(&docs.state(&pool, id).await.unwrap()).unwrap());
+        assert_eq!(alice.0.title(), "buy oat milk");
+        docs.apply(&pool, id, &alice.edit(0, 0, "🥛 ")).await.unwrap();
+
+        // Typing saves the title under the same version, and saving the edit moves it on once
+        assert_eq!(get_todo(&pool, id).await.unwrap().version, 2);
+        assert!(docs.commit(&pool, id).await.unwrap());
+        assert!(!docs.commit(&pool, id).await.unwrap());
+        assert_eq!(get_todo(&pool, id).await.unwrap().version, 3);
+
+        docs.close(id, "alice");
+        assert_eq!(docs.loaded(), 1);
+        docs.leave("bob");
+        assert_eq!(docs.loaded(), 0);
+        assert_eq!(get_todo(&pool, id).await.unwrap().title, "🥛 buy oat milk");
+    }
+
+    #[rocket::async_test]
+    async fn failed_save_leaves_the_document_alone() {
+        let pool = test_pool().await;
+        let docs = TitleDocs::default();
+        let id = todo(&pool, "walk dog").await;
+        docs.open(id, "alice");
+        let alice = Client(TitleDoc::from_state(&docs.state(&pool, id).await.unwrap()).unwrap());
+
+        sqlx::query("DELETE FROM todos WHERE id = ?").bind(id).execute(&pool).await.unwrap();
+        assert!(docs.apply(&pool, id, &alice.edit(0, 0, "⏰ ")).await.is_err());
+
+        let server = TitleDoc::from_state(&docs.state(&pool, id).await.unwrap()).unwrap();
+        assert_eq!(server.title(), "walk dog");
+    }
+}
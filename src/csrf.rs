@@ -0,0 +1,131 @@
+// Cross-site request forgery protection. The session cookie is SameSite=None so it
+// survives inside Farcaster frames, which also means any other site's page can send
+// it along. So every request that can change something (anything but GET, HEAD and
+// OPTIONS, plus WebSocket handshakes, which browsers never restrict) has to come
+// from one of our own pages: its Origin, or failing that its Referer, must name
+// this host or an allowed origin. Requests carrying neither don't come from a
+// browser and go through.
+use crate::config::var;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::{Data, Request, Route};
+
+// Where rejected requests are sent instead of the route they asked for
+const REJECTED: &str = "/cross-origin-rejected";
+
+pub fn routes() -> Vec<Route> {
+    routes![cross_origin_rejected]
+}
+
+pub struct SameOrigin {
+    // Other origins whose pages may write, like https://app.example.com
+    allowed: Vec<String>,
+}
+
+impl SameOrigin {
+    // Extra origins come from ALLOWED_ORIGINS, separated by commas
+    pub fn from_env() -> Self {
+        let allowed: String = var("ALLOWED_ORIGINS", String::new());
+        SameOrigin {
+            allowed: allowed
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_ascii_lowercase())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SameOrigin {
+    fn info(&self) -> Info {
+        Info {
+            name: "Same Origin",
+            kind: Kind::Request,
+        }
+    }
+
+    // Fairings can't answer a request themselves, so point it at a route that refuses it
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let safe = matches!(req.method(), Method::Get | Method::Head | Method::Options);
+        let websocket = req.headers().get_one("Upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        if safe && !websocket {
+            return;
+        }
+
+        let headers = req.headers();
+        let host = req.host().map(|host| host.to_string());
+        if is_allowed(headers.get_one("Origin"), headers.get_one("Referer"), host.as_deref(), &self.allowed) {
+            return;
+        }
+        println!("Refused cross-origin {} {} from {:?}", req.method(), req.uri(), headers.get_one("Origin").or(headers.get_one("Referer")));
+        req.set_method(Method::Get);
+        req.set_uri(Origin::path_only(REJECTED));
+    }
+}
+
+#[get("/cross-origin-rejected")]
+fn cross_origin_rejected() -> Status {
+    Status::Forbidden
+}
+
+// Whether a request with these headers comes from a page we trust. `host` is the
+// Host header: a page served by this host may write whatever scheme it came over,
+// since TLS often ends at a proxy in front of us.
+fn is_allowed(origin: Option<&str>, referer: Option<&str>, host: Option<&str>, allowed: &[String]) -> bool {
+    let Some(source) = origin.or(referer) else {
+        return true;
+    };
+    // Includes `Origin: null` from sandboxed frames and privacy-sensitive redirects
+    let Some((scheme, authority)) = split_origin(source) else {
+        return false;
+    };
+    let origin = format!("{}://{}", scheme, authority);
+    allowed.contains(&origin) || host.is_some_and(|host| host.eq_ignore_ascii_case(&authority))
+}
+
+// The lowercase scheme and host[:port] of an origin or URL
+fn split_origin(url: &str) -> Option<(String, String)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    // Credentials in a Referer would make the host ambiguous
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    Some((scheme.to_ascii_lowercase(), authority.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_allowed;
+
+    #[test]
+    fn pages_of_this_host_may_write() {
+        let host = Some("todo.example.com");
+        assert!(is_allowed(Some("https://todo.example.com"), None, host, &[]));
+        assert!(is_allowed(Some("http://TODO.example.com"), None, host, &[]));
+        assert!(is_allowed(None, Some("https://todo.example.com/lists/work?tags=x"), host, &[]));
+        assert!(is_allowed(Some("http://localhost:8000"), None, Some("localhost:8000"), &[]));
+    }
+
+    #[test]
+    fn other_sites_may_not() {
+        let host = Some("todo.example.com");
+        assert!(!is_allowed(Some("https://evil.example"), None, host, &[]));
+        // The Origin wins over a Referer that looks right
+        assert!(!is_allowed(Some("https://evil.example"), Some("https://todo.example.com/"), host, &[]));
+        assert!(!is_allowed(Some("https://todo.example.com.evil.example"), None, host, &[]));
+        assert!(!is_allowed(Some("http://localhost:8001"), None, Some("localhost:8000"), &[]));
+        assert!(!is_allowed(Some("null"), None, host, &[]));
+        assert!(!is_allowed(None, Some("https://todo.example.com@evil.example/"), host, &[]));
+    }
+
+    #[test]
+    fn allowed_origins_and_non_browsers() {
+        let allowed = vec!["https://app.example.com".to_string()];
+        assert!(is_allowed(Some("https://app.example.com"), None, Some("api.example.com"), &allowed));
+        assert!(!is_allowed(Some("http://app.example.com"), None, Some("api.example.com"), &allowed));
+        assert!(is_allowed(None, None, Some("api.example.com"), &[]));
+    }
+}
@@ -1,100 +1,1210 @@
+use crate::config::var;
+use crate::rank;
+use crate::recurrence::Rule;
+use crate::schedule::Due;
 use crate::DB_URL;
-use serde::Serialize;
-use sqlx::migrate::MigrateDatabase;
-use sqlx::{Error, Pool, Sqlite, SqlitePool};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::{MigrateDatabase, MigrateError, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::error::ErrorKind;
+use sqlx::pool::PoolConnection;
+use sqlx::{Error, QueryBuilder, Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Todo {
     pub id: i64,
+    pub list_id: i64,
     pub title: String,
     pub completed: bool,
+    // Bumped on every write; the ETag for optimistic concurrency
+    pub version: i64,
+    pub due_at: Option<DateTime<Utc>>,
+    pub remind_at: Option<DateTime<Utc>>,
+    pub priority: Priority,
+    // Sorted, as normalized by parse_tags
+    pub tags: Vec<String>,
+    // Rank in the list's manual order, or among its siblings for a subtask; see rank.rs
+    pub position: String,
+    // The todo this is a subtask of
+    pub parent_id: Option<i64>,
+    // Levels above this todo: 0 at the top, at most MAX_DEPTH - 1
+    pub depth: u32,
+    // Loaded with the todo, in manual order
+    #[schema(no_recursion)]
+    pub subtasks: Vec<Todo>,
+    // Subtasks done, counting every level below this todo
+    pub progress: Progress,
+    // An RFC 5545 recurrence rule; completing the todo adds its next occurrence
+    pub recurrence: Option<String>,
 }
 
-async fn conn() -> Result<Pool<Sqlite>, sqlx::Error> {
-    SqlitePool::connect(DB_URL).await
+// How many levels of todos a list may nest: todos, their subtasks, and theirs
+pub const MAX_DEPTH: u32 = 3;
+// The deepest a todo can sit and still take subtasks, for the cards to know
+pub const DEEPEST_PARENT: u32 = MAX_DEPTH - 1;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+// What a new todo starts out with
+#[derive(Default)]
+pub struct TodoDraft<'a> {
+    pub title: &'a str,
+    // Makes it a subtask; must be a todo of the same list
+    pub parent_id: Option<i64>,
+    pub due_at: Option<DateTime<Utc>>,
+    pub remind_at: Option<DateTime<Utc>>,
+    pub priority: Priority,
+    pub tags: &'a [String],
+    // A rule as normalized by recurrence::Rule
+    pub recurrence: Option<&'a str>,
+}
+
+// What else changed along with a todo's completed state
+#[derive(Debug, Default)]
+pub struct Completion {
+    // Subtasks completed or parents reopened with it
+    pub cascaded: Vec<i64>,
+    // The next occurrence of a recurring todo that was completed
+    pub next: Option<i64>,
+}
+
+// Changes to one todo, written together under a single new version. None leaves a
+// field as it is.
+#[derive(Debug, Default)]
+pub struct TodoChanges {
+    // The title along with the collaborative document it was merged from
+    pub title: Option<(String, Vec<u8>)>,
+    pub completed: Option<bool>,
+    pub due_at: Option<Option<DateTime<Utc>>>,
+    pub remind_at: Option<Option<DateTime<Utc>>>,
+    pub priority: Option<Priority>,
+    pub tags: Option<Vec<String>>,
+    // A rule as normalized by recurrence::Rule
+    pub recurrence: Option<Option<String>>,
+}
+
+impl TodoChanges {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.completed.is_none()
+            && self.due_at.is_none()
+            && self.remind_at.is_none()
+            && self.priority.is_none()
+            && self.tags.is_none()
+            && self.recurrence.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, FromFormField, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
 }
 
-pub async fn maybe_create_database() -> Result<(), Error> {
+impl Priority {
+    fn from_db(value: i64) -> Self {
+        match value {
+            1 => Priority::Low,
+            2 => Priority::Medium,
+            3 => Priority::High,
+            _ => Priority::None,
+        }
+    }
+
+    fn to_db(self) -> i64 {
+        self as i64
+    }
+}
+
+// Tags as typed: separated by commas or spaces, with or without a leading `#`.
+// Stored lowercase, without duplicates.
+pub fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|tag| tag.trim_start_matches('#').to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Which todos of a list to fetch and in what order. Also the query string of the
+// list pages, so filtered views can be bookmarked. It picks top-level todos; their
+// subtasks come along whether they match or not.
+#[derive(Debug, Clone, Default, Serialize, FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TodoQuery {
+    // Only done (true) or open (false) todos
+    pub completed: Option<bool>,
+    // Todos carrying every one of these tags; repeat the parameter or separate with commas
+    pub tags: Vec<String>,
+    pub priority: Option<Priority>,
+    // Case-insensitive substring of the title
+    pub text: Option<String>,
+    // Only the overdue, today's or upcoming todos
+    pub due: Option<Due>,
+    #[field(default = Sort::Manual)]
+    pub sort: Sort,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl TodoQuery {
+    // Whether this leaves out any todos, as opposed to only sorting them
+    pub fn is_filtered(&self) -> bool {
+        self.completed.is_some()
+            || !self.tags().is_empty()
+            || self.priority.is_some()
+            || self.text().is_some()
+            || self.due.is_some()
+            || self.limit.is_some()
+            || self.offset.is_some()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        parse_tags(&self.tags.join(","))
+    }
+
+    fn text(&self) -> Option<&str> {
+        self.text.as_deref().map(str::trim).filter(|text| !text.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, FromFormField, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    // As arranged by dragging; new todos go on top
+    #[default]
+    Manual,
+    Newest,
+    Oldest,
+    // Highest first
+    Priority,
+    // Soonest first, undated last
+    Due,
+    Title,
+}
+
+impl Sort {
+    fn order_by(self) -> &'static str {
+        match self {
+            Sort::Manual => "position, id DESC",
+            Sort::Newest => "id DESC",
+            Sort::Oldest => "id ASC",
+            Sort::Priority => "priority DESC, id DESC",
+            Sort::Due => "due_at IS NULL, due_at, id DESC",
+            Sort::Title => "title COLLATE NOCASE, id DESC",
+        }
+    }
+}
+
+// Columns every todo query selects, in the order todo_from_row reads them
+const TODO_COLUMNS: &str = "id, list_id, title, completed, version, due_at, remind_at, priority, \
+    (SELECT group_concat(tags.name, ',') FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = todos.id), \
+    position, parent_id, recurrence";
+
+type TodoRow = (i64, i64, String, i8, i64, Option<i64>, Option<i64>, i64, Option<String>, String, Option<i64>, Option<String>);
+
+// Every todo below the one bound first, at any depth
+const DESCENDANTS: &str = "WITH RECURSIVE tree(id) AS ( \
+    SELECT id FROM todos WHERE parent_id = ? \
+    UNION ALL SELECT todos.id FROM todos JOIN tree ON todos.parent_id = tree.id)";
+
+// The todo bound first and every todo above it, up to the top-level one
+const ANCESTORS: &str = "WITH RECURSIVE ancestors(id, parent_id) AS ( \
+    SELECT id, parent_id FROM todos WHERE id = ? \
+    UNION ALL SELECT todos.id, todos.parent_id FROM todos JOIN ancestors ON todos.id = ancestors.parent_id)";
+
+fn todo_from_row(row: TodoRow) -> Todo {
+    Todo {
+        id: row.0,
+        list_id: row.1,
+        title: row.2,
+        completed: row.3 == 1,
+        version: row.4,
+        due_at: row.5.and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        remind_at: row.6.and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        priority: Priority::from_db(row.7),
+        tags: row.8.as_deref().map(parse_tags).unwrap_or_default(),
+        position: row.9,
+        parent_id: row.10,
+        depth: 0,
+        subtasks: Vec::new(),
+        progress: Progress::default(),
+        recurrence: row.11,
+    }
+}
+
+// Hang each todo's subtasks under it, from the subtasks of the whole group grouped
+// by parent, and add up the progress on the way back
+fn build_tree(todo: &mut Todo, children: &mut HashMap<i64, Vec<Todo>>) {
+    todo.subtasks = children.remove(&todo.id).unwrap_or_default();
+    todo.progress = Progress::default();
+    for subtask in &mut todo.subtasks {
+        subtask.depth = todo.depth + 1;
+        build_tree(subtask, children);
+        todo.progress.done += subtask.progress.done + subtask.completed as usize;
+        todo.progress.total += subtask.progress.total + 1;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct List {
+    pub id: i64,
+    pub slug: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct User {
+    pub id: i64,
+    // None for accounts created through Sign In With Farcaster
+    pub username: Option<String>,
+    pub fid: Option<i64>,
+    pub farcaster_username: Option<String>,
+}
+
+// Pool settings, read from the environment with sensible defaults
+pub struct DbConfig {
+    pub max_connections: u32,
+    pub busy_timeout: Duration,
+    pub wal: bool,
+}
+
+impl DbConfig {
+    pub fn from_env() -> Self {
+        DbConfig {
+            max_connections: var("DB_MAX_CONNECTIONS", 8),
+            busy_timeout: Duration::from_millis(var("DB_BUSY_TIMEOUT_MS", 5000)),
+            wal: var("DB_WAL", true),
+        }
+    }
+}
+
+// Open the single shared pool used by every route and WebSocket
+pub async fn create_pool(config: &DbConfig) -> Result<SqlitePool, Error> {
     if !Sqlite::database_exists(DB_URL).await.unwrap_or(false) {
         info!("Creating database {}", DB_URL);
         Sqlite::create_database(DB_URL).await?
     } else {
         info!("Database already exists");
     }
-    sqlx::query(
-        "
-    CREATE TABLE IF NOT EXISTS todos (
-       id INTEGER PRIMARY KEY,
-       title TEXT NOT NULL,
-       completed INTEGER
-    )
-",
-    )
-        .execute(&conn().await?)
-        .await?;
+
+    let journal_mode = if config.wal {
+        SqliteJournalMode::Wal
+    } else {
+        SqliteJournalMode::Delete
+    };
+    let options = SqliteConnectOptions::from_str(DB_URL)?
+        .busy_timeout(config.busy_timeout)
+        .journal_mode(journal_mode);
+
+    info!(
+        "Opening pool with {} connections, busy timeout {:?}, journal mode {:?}",
+        config.max_connections, config.busy_timeout, journal_mode
+    );
+    SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .connect_with(options)
+        .await
+}
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+// Apply pending migrations, refusing to touch a database from a newer build
+pub async fn run_migrations(pool: &SqlitePool) -> Result<(), MigrateError> {
+    let latest_known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+    let applied: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success = 1")
+        .fetch_one(pool)
+        .await
+        .unwrap_or(None);
+
+    if let Some(applied) = applied {
+        if applied > latest_known {
+            error!("Database schema version {} is newer than this binary ({})", applied, latest_known);
+            return Err(MigrateError::VersionMissing(applied));
+        }
+    }
+
+    MIGRATOR.run(pool).await?;
+    info!("Database schema at version {}", latest_known);
     Ok(())
 }
 
-pub async fn add_todo(title: &String) -> Result<i64, DbError> {
-    let res = sqlx::query("INSERT INTO todos (title, completed) VALUES (?, 0)")
-        .bind(title)
-        .execute(&conn().await?)
+// A fresh in-memory database. Each connection would get its own, so there is just
+// the one, kept for the life of the pool.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+// A transaction that takes the write lock as it begins. pool.begin() starts a deferred
+// one, and under WAL a deferred transaction that reads, sees another writer commit
+// and then writes fails with SQLITE_BUSY; these queue behind each other instead.
+// Dropped without a commit, it rolls back before the connection goes back.
+struct Immediate {
+    conn: Option<PoolConnection<Sqlite>>,
+}
+
+impl Immediate {
+    async fn begin(pool: &SqlitePool) -> Result<Self, DbError> {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        Ok(Immediate { conn: Some(conn) })
+    }
+
+    async fn commit(mut self) -> Result<(), DbError> {
+        sqlx::query("COMMIT").execute(&mut *self).await?;
+        self.conn = None;
+        Ok(())
+    }
+}
+
+impl Deref for Immediate {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        self.conn.as_ref().expect("transaction already finished")
+    }
+}
+
+impl DerefMut for Immediate {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        self.conn.as_mut().expect("transaction already finished")
+    }
+}
+
+impl Drop for Immediate {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        rocket::tokio::spawn(async move {
+            if let Err(e) = sqlx::query("ROLLBACK").execute(&mut *conn).await {
+                // Closing the connection is the other way to end its transaction
+                error!("Failed to roll back: {:?}", e);
+                drop(conn.detach());
+            }
+        });
+    }
+}
+
+pub async fn add_list(pool: &SqlitePool, slug: &str, name: &str) -> Result<i64, DbError> {
+    let res = sqlx::query("INSERT INTO lists (slug, name) VALUES (?, ?)")
+        .bind(slug)
+        .bind(name)
+        .execute(pool)
         .await?;
-    info!("Todo added with id {:?}", res.last_insert_rowid());
+    info!("List {} added with id {:?}", slug, res.last_insert_rowid());
     Ok(res.last_insert_rowid())
 }
 
-pub async fn get_todo(id: i64) -> Result<Todo, DbError> {
-    let row: (i64, String, i8) =
-        sqlx::query_as("SELECT id, title, completed FROM todos WHERE id=?")
+pub async fn get_list(pool: &SqlitePool, slug: &str) -> Result<List, DbError> {
+    let row: (i64, String, String) = sqlx::query_as("SELECT id, slug, name FROM lists WHERE slug=?")
+        .bind(slug)
+        .fetch_one(pool)
+        .await?;
+    Ok(List {
+        id: row.0,
+        slug: row.1,
+        name: row.2,
+    })
+}
+
+pub async fn get_lists(pool: &SqlitePool) -> Result<Vec<List>, DbError> {
+    let rows: Vec<(i64, String, String)> = sqlx::query_as("SELECT id, slug, name FROM lists ORDER BY id")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| List {
+            id: row.0,
+            slug: row.1,
+            name: row.2,
+        })
+        .collect())
+}
+
+pub async fn add_user(pool: &SqlitePool, username: &str, password_hash: &str) -> Result<i64, DbError> {
+    let res = sqlx::query("INSERT INTO users (username, password_hash) VALUES (?, ?)")
+        .bind(username)
+        .bind(password_hash)
+        .execute(pool)
+        .await?;
+    info!("User {} added with id {:?}", username, res.last_insert_rowid());
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn get_user(pool: &SqlitePool, id: i64) -> Result<User, DbError> {
+    let row: (i64, Option<String>, Option<i64>, Option<String>) =
+        sqlx::query_as("SELECT id, username, fid, farcaster_username FROM users WHERE id=?")
             .bind(id)
-            .fetch_one(&conn().await?)
+            .fetch_one(pool)
+            .await?;
+    Ok(User {
+        id: row.0,
+        username: row.1,
+        fid: row.2,
+        farcaster_username: row.3,
+    })
+}
+
+pub async fn get_user_by_fid(pool: &SqlitePool, fid: i64) -> Result<User, DbError> {
+    let row: (i64, Option<String>, Option<i64>, Option<String>) =
+        sqlx::query_as("SELECT id, username, fid, farcaster_username FROM users WHERE fid=?")
+            .bind(fid)
+            .fetch_one(pool)
             .await?;
-    Ok(Todo {
+    Ok(User {
         id: row.0,
-        title: row.1,
-        completed: row.2 == 1,
+        username: row.1,
+        fid: row.2,
+        farcaster_username: row.3,
     })
 }
 
-pub async fn update_todo(id: i64, title: &String) -> Result<(), DbError> {
-    sqlx::query(
-        "UPDATE todos SET title = ? WHERE id=?",
+pub async fn add_farcaster_user(pool: &SqlitePool, fid: i64, farcaster_username: Option<&str>) -> Result<i64, DbError> {
+    let res = sqlx::query("INSERT INTO users (fid, farcaster_username) VALUES (?, ?)")
+        .bind(fid)
+        .bind(farcaster_username)
+        .execute(pool)
+        .await?;
+    info!("Farcaster user {} added with id {:?}", fid, res.last_insert_rowid());
+    Ok(res.last_insert_rowid())
+}
+
+// Attach a FID to an account, or refresh the Farcaster username of one already attached
+pub async fn link_farcaster(pool: &SqlitePool, id: i64, fid: i64, farcaster_username: Option<&str>) -> Result<(), DbError> {
+    let res = sqlx::query("UPDATE users SET fid = ?, farcaster_username = COALESCE(?, farcaster_username) WHERE id=?")
+        .bind(fid)
+        .bind(farcaster_username)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    ensure_found(res.rows_affected())
+}
+
+// The user and their stored password hash, for checking a login
+pub async fn get_user_login(pool: &SqlitePool, username: &str) -> Result<(User, String), DbError> {
+    let row: (i64, String, String, Option<i64>, Option<String>) = sqlx::query_as(
+        "SELECT id, username, password_hash, fid, farcaster_username FROM users WHERE username=? AND password_hash IS NOT NULL",
+    )
+        .bind(username)
+        .fetch_one(pool)
+        .await?;
+    Ok((
+        User {
+            id: row.0,
+            username: Some(row.1),
+            fid: row.3,
+            farcaster_username: row.4,
+        },
+        row.2,
+    ))
+}
+
+// New todos go on top of the list's manual order, new subtasks at the bottom of
+// their parent's, like items added to a checklist
+pub async fn add_todo(pool: &SqlitePool, list_id: i64, draft: &TodoDraft<'_>) -> Result<i64, DbError> {
+    if let Some(parent_id) = draft.parent_id {
+        let parent = get_todo(pool, parent_id).await?;
+        if parent.list_id != list_id {
+            return Err(DbError::NotFound(Error::RowNotFound));
+        }
+        if parent.depth + 1 >= MAX_DEPTH {
+            return Err(DbError::TooDeep { parent_id, depth: parent.depth + 1 });
+        }
+    }
+
+    let mut tx = Immediate::begin(pool).await?;
+    let (first, last): (Option<String>, Option<String>) =
+        sqlx::query_as("SELECT MIN(position), MAX(position) FROM todos WHERE list_id=? AND parent_id IS ?")
+            .bind(list_id)
+            .bind(draft.parent_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    let position = match draft.parent_id {
+        Some(_) => rank::between(last.as_deref(), None),
+        None => rank::between(None, first.as_deref()),
+    }
+        .ok_or_else(|| DbError::Other(Error::Decode(format!("list {} has an invalid position", list_id).into())))?;
+    let id = insert_todo(&mut tx, list_id, draft, &position).await?;
+    tx.commit().await?;
+    info!("Todo added with id {:?}", id);
+    Ok(id)
+}
+
+async fn insert_todo(conn: &mut SqliteConnection, list_id: i64, draft: &TodoDraft<'_>, position: &str) -> Result<i64, DbError> {
+    let res = sqlx::query(
+        "INSERT INTO todos (list_id, title, completed, due_at, remind_at, priority, position, parent_id, recurrence) \
+         VALUES (?, ?, 0, ?, ?, ?, ?, ?, ?)",
     )
+        .bind(list_id)
+        .bind(draft.title)
+        .bind(draft.due_at.map(|at| at.timestamp()))
+        .bind(draft.remind_at.map(|at| at.timestamp()))
+        .bind(draft.priority.to_db())
+        .bind(position)
+        .bind(draft.parent_id)
+        .bind(draft.recurrence)
+        .execute(&mut *conn)
+        .await?;
+    let id = res.last_insert_rowid();
+    insert_tags(conn, id, draft.tags).await?;
+    Ok(id)
+}
+
+// A todo with its subtasks
+pub async fn get_todo(pool: &SqlitePool, id: i64) -> Result<Todo, DbError> {
+    get_todos_by_id(pool, &[id]).await?.pop().ok_or(DbError::NotFound(Error::RowNotFound))
+}
+
+// Todos with their subtasks, however many, in three queries. Ids that don't exist
+// are left out; the rest come back in id order.
+pub async fn get_todos_by_id(pool: &SqlitePool, ids: &[i64]) -> Result<Vec<Todo>, DbError> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sql = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM todos WHERE id IN (", TODO_COLUMNS));
+    let mut bound = sql.separated(", ");
+    for id in ids {
+        bound.push_bind(*id);
+    }
+    sql.push(") ORDER BY id");
+    let rows: Vec<TodoRow> = sql.build_query_as().fetch_all(pool).await?;
+    let mut todos: Vec<Todo> = rows.into_iter().map(todo_from_row).collect();
+
+    // Walk up from each todo at once, counting the steps to the top
+    let mut sql = QueryBuilder::<Sqlite>::new("WITH RECURSIVE up(id, parent_id, depth) AS (SELECT id, parent_id, 0 FROM todos WHERE id IN (");
+    let mut bound = sql.separated(", ");
+    for id in ids {
+        bound.push_bind(*id);
+    }
+    sql.push(") UNION ALL SELECT up.id, todos.parent_id, up.depth + 1 FROM up JOIN todos ON todos.id = up.parent_id) ")
+        .push("SELECT id, MAX(depth) FROM up GROUP BY id");
+    let depths: HashMap<i64, u32> = sql.build_query_as().fetch_all(pool).await?.into_iter().collect();
+    for todo in &mut todos {
+        todo.depth = depths.get(&todo.id).copied().unwrap_or(0);
+    }
+    attach_subtasks(pool, &mut todos).await?;
+    Ok(todos)
+}
+
+// The top-level todo a todo sits under, or the todo itself
+pub async fn root_todo_id(pool: &SqlitePool, id: i64) -> Result<i64, DbError> {
+    ancestor_ids(pool, id).await?.last().copied().ok_or(DbError::NotFound(Error::RowNotFound))
+}
+
+// The todo, its parent, and so on up to the top; empty if there's no such todo
+async fn ancestor_ids(pool: &SqlitePool, id: i64) -> Result<Vec<i64>, DbError> {
+    let ids = sqlx::query_scalar(&format!("{} SELECT id FROM ancestors", ANCESTORS))
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+    Ok(ids)
+}
+
+// Load the subtasks of a group of todos, at every depth, in one query
+async fn attach_subtasks(pool: &SqlitePool, todos: &mut [Todo]) -> Result<(), DbError> {
+    if todos.is_empty() {
+        return Ok(());
+    }
+    let mut sql = QueryBuilder::<Sqlite>::new("WITH RECURSIVE tree(id) AS (SELECT id FROM todos WHERE parent_id IN (");
+    let mut ids = sql.separated(", ");
+    for todo in todos.iter() {
+        ids.push_bind(todo.id);
+    }
+    sql.push(") UNION ALL SELECT todos.id FROM todos JOIN tree ON todos.parent_id = tree.id) ")
+        .push(format!("SELECT {} FROM todos WHERE id IN (SELECT id FROM tree) ORDER BY position, id DESC", TODO_COLUMNS));
+
+    let rows: Vec<TodoRow> = sql.build_query_as().fetch_all(pool).await?;
+    let mut children: HashMap<i64, Vec<Todo>> = HashMap::new();
+    for subtask in rows.into_iter().map(todo_from_row) {
+        if let Some(parent_id) = subtask.parent_id {
+            children.entry(parent_id).or_default().push(subtask);
+        }
+    }
+    for todo in todos {
+        build_tree(todo, &mut children);
+    }
+    Ok(())
+}
+
+// Write a set of changes in one transaction, so either all of them land or none do.
+// With an expected version, fails with VersionMismatch if the todo changed since.
+// A new reminder time goes out again even if the old one already did.
+pub async fn edit_todo(pool: &SqlitePool, id: i64, changes: &TodoChanges, expected_version: Option<i64>) -> Result<Completion, DbError> {
+    let mut tx = pool.begin().await?;
+    let mut sql = QueryBuilder::<Sqlite>::new("UPDATE todos SET version = version + 1");
+    if let Some((title, title_state)) = &changes.title {
+        sql.push(", title = ").push_bind(title).push(", title_state = ").push_bind(title_state);
+    }
+    if let Some(completed) = changes.completed {
+        sql.push(", completed = ").push_bind(completed as i8);
+    }
+    if let Some(due_at) = changes.due_at {
+        sql.push(", due_at = ").push_bind(due_at.map(|at| at.timestamp()));
+    }
+    if let Some(remind_at) = changes.remind_at {
+        let remind_at = remind_at.map(|at| at.timestamp());
+        sql.push(", reminder_sent = CASE WHEN remind_at IS ")
+            .push_bind(remind_at)
+            .push(" THEN reminder_sent ELSE 0 END, remind_at = ")
+            .push_bind(remind_at);
+    }
+    if let Some(priority) = changes.priority {
+        sql.push(", priority = ").push_bind(priority.to_db());
+    }
+    if let Some(recurrence) = &changes.recurrence {
+        sql.push(", recurrence = ").push_bind(recurrence);
+    }
+    sql.push(" WHERE id = ")
+        .push_bind(id)
+        .push(" AND (")
+        .push_bind(expected_version)
+        .push(" IS NULL OR version = ")
+        .push_bind(expected_version)
+        .push(")");
+    let res = sql.build().execute(&mut *tx).await?;
+    if res.rows_affected() == 0 {
+        tx.rollback().await?;
+        return ensure_written(pool, id, expected_version, 0).await.map(|_| Completion::default());
+    }
+
+    if let Some(tags) = &changes.tags {
+        sqlx::query("DELETE FROM todo_tags WHERE todo_id=?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        insert_tags(&mut tx, id, tags).await?;
+    }
+    let completion = match changes.completed {
+        Some(completed) => cascade_completed(&mut tx, id, completed).await?,
+        None => Completion::default(),
+    };
+    tx.commit().await?;
+    Ok(completion)
+}
+
+// The title and its collaborative document, if one has been saved yet
+pub async fn get_todo_title_state(pool: &SqlitePool, id: i64) -> Result<(String, Option<Vec<u8>>), DbError> {
+    let row: (String, Option<Vec<u8>>) = sqlx::query_as("SELECT title, title_state FROM todos WHERE id=?")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(row)
+}
+
+// Attach the first collaborative document to a title; the title itself is unchanged,
+// so neither is the version
+pub async fn init_todo_title_state(pool: &SqlitePool, id: i64, title_state: &[u8]) -> Result<(), DbError> {
+    let res = sqlx::query("UPDATE todos SET title_state = ? WHERE id=? AND title_state IS NULL")
+        .bind(title_state)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    ensure_found(res.rows_affected())
+}
+
+// Save a title as typed in the editor. The version stays: keystrokes aren't saves,
+// and a version per key would fail every If-Match while someone types.
+pub async fn save_todo_title(pool: &SqlitePool, id: i64, title: &str, title_state: &[u8]) -> Result<(), DbError> {
+    let res = sqlx::query("UPDATE todos SET title = ?, title_state = ? WHERE id=?")
         .bind(title)
+        .bind(title_state)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    ensure_found(res.rows_affected())
+}
+
+pub async fn set_todo_completed(pool: &SqlitePool, id: i64, completed: bool, expected_version: Option<i64>) -> Result<Completion, DbError> {
+    let changes = TodoChanges {
+        completed: Some(completed),
+        ..Default::default()
+    };
+    edit_todo(pool, id, &changes, expected_version).await
+}
+
+// Completing a todo completes its subtasks, and reopening one reopens the todos it's
+// under, so a done todo never has open work left. Completing a recurring todo adds
+// its next occurrence in the same transaction. Recurring subtasks completed along
+// with it don't: their next occurrences would be open work under a done todo.
+async fn cascade_completed(tx: &mut Transaction<'_, Sqlite>, id: i64, completed: bool) -> Result<Completion, DbError> {
+    let cascade = if completed { DESCENDANTS } else { ANCESTORS };
+    let source = if completed { "tree" } else { "ancestors" };
+    let cascaded = sqlx::query_scalar(&format!(
+        "{} UPDATE todos SET completed = ?, version = version + 1 WHERE completed != ? AND id IN (SELECT id FROM {}) RETURNING id",
+        cascade, source
+    ))
+        .bind(id)
+        .bind(completed as i8)
+        .bind(completed as i8)
+        .fetch_all(&mut **tx)
+        .await?;
+    let next = if completed { add_next_occurrence(tx, id).await? } else { None };
+    Ok(Completion { cascaded, next })
+}
+
+// Add the occurrence after a recurring todo that was just completed, if its rule has
+// one left: a copy without subtasks, due on the rule's next date that's still ahead,
+// with the reminder as far ahead of it as before. It goes right above the completed one.
+// A todo gets one next occurrence, however often it's reopened and completed again.
+async fn add_next_occurrence(tx: &mut Transaction<'_, Sqlite>, id: i64) -> Result<Option<i64>, DbError> {
+    let next_id: Option<i64> = sqlx::query_scalar("SELECT next_id FROM todos WHERE id=?")
+        .bind(id)
+        .fetch_one(&mut **tx)
+        .await?;
+    if next_id.is_some() {
+        return Ok(None);
+    }
+    let row: TodoRow = sqlx::query_as(&format!("SELECT {} FROM todos WHERE id=?", TODO_COLUMNS))
+        .bind(id)
+        .fetch_one(&mut **tx)
+        .await?;
+    let todo = todo_from_row(row);
+    // Rules are checked when saved, so one that no longer reads just stops repeating
+    let Some(rule) = todo.recurrence.as_deref().and_then(|rule| Rule::parse(rule).ok()) else {
+        return Ok(None);
+    };
+    let now = Utc::now();
+    let due_at = todo.due_at.unwrap_or(now);
+    let Some((next_due_at, next_rule)) = rule.upcoming(due_at, now, &Local) else {
+        return Ok(None);
+    };
+
+    let before: Option<String> =
+        sqlx::query_scalar("SELECT MAX(position) FROM todos WHERE list_id=? AND parent_id IS ? AND position < ?")
+            .bind(todo.list_id)
+            .bind(todo.parent_id)
+            .bind(&todo.position)
+            .fetch_one(&mut **tx)
+            .await?;
+    let position = rank::between(before.as_deref(), Some(&todo.position))
+        .ok_or_else(|| DbError::Other(Error::Decode(format!("todo {} has an invalid position", id).into())))?;
+    let next_rule = next_rule.to_string();
+    let draft = TodoDraft {
+        title: &todo.title,
+        parent_id: todo.parent_id,
+        due_at: Some(next_due_at),
+        remind_at: todo.remind_at.map(|at| next_due_at - (due_at - at)),
+        priority: todo.priority,
+        tags: &todo.tags,
+        recurrence: Some(&next_rule),
+    };
+    let next = insert_todo(tx, todo.list_id, &draft, &position).await?;
+    sqlx::query("UPDATE todos SET next_id = ? WHERE id=?")
+        .bind(next)
         .bind(id)
-        .execute(&conn().await?)
+        .execute(&mut **tx)
         .await?;
+    info!("Todo {} repeats as {}", id, next);
+    Ok(Some(next))
+}
+
+async fn insert_tags(conn: &mut SqliteConnection, id: i64, tags: &[String]) -> Result<(), DbError> {
+    for tag in tags {
+        sqlx::query("INSERT OR IGNORE INTO tags (name) VALUES (?)")
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO todo_tags (todo_id, tag_id) SELECT ?, id FROM tags WHERE name=?")
+            .bind(id)
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+    }
     Ok(())
 }
 
-pub async fn delete_todo(id: i64) -> Result<(), DbError> {
-    sqlx::query("DELETE FROM todos WHERE id = ?")
+// Place a todo right after `before` and right before `after`, both todos of the same
+// list and parent (None for either end). Only the moved todo is written, and its version stays:
+// the order is the list's, not part of the todo. Returns the new position.
+pub async fn move_todo(pool: &SqlitePool, id: i64, before: Option<i64>, after: Option<i64>) -> Result<String, DbError> {
+    let mut tx = Immediate::begin(pool).await?;
+    let list_id: i64 = sqlx::query_scalar("SELECT list_id FROM todos WHERE id=?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let low = neighbor_position(&mut tx, list_id, id, before).await?;
+    let high = neighbor_position(&mut tx, list_id, id, after).await?;
+
+    // Neighbors out of order mean the client's view of the list is stale
+    let Some(position) = rank::between(low.as_deref(), high.as_deref()) else {
+        return Err(DbError::OutOfOrder);
+    };
+    sqlx::query("UPDATE todos SET position = ? WHERE id=?")
+        .bind(&position)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(position)
+}
+
+// The position of a neighbor in a move, which must be another todo of the same list
+// with the same parent
+async fn neighbor_position(conn: &mut SqliteConnection, list_id: i64, id: i64, neighbor: Option<i64>) -> Result<Option<String>, DbError> {
+    let Some(neighbor) = neighbor else {
+        return Ok(None);
+    };
+    let position: String = sqlx::query_scalar(
+        "SELECT position FROM todos WHERE id=? AND list_id=? AND id != ? AND parent_id IS (SELECT parent_id FROM todos WHERE id=?)",
+    )
+        .bind(neighbor)
+        .bind(list_id)
+        .bind(id)
+        .bind(id)
+        .fetch_one(conn)
+        .await?;
+    Ok(Some(position))
+}
+
+// Claim the open todos whose reminder time has passed, so each reminder is sent once
+pub async fn take_due_reminders(pool: &SqlitePool, now: DateTime<Utc>) -> Result<Vec<Todo>, DbError> {
+    let rows: Vec<TodoRow> = sqlx::query_as(&format!(
+        "UPDATE todos SET reminder_sent = 1 WHERE reminder_sent = 0 AND completed = 0 AND remind_at <= ? RETURNING {}",
+        TODO_COLUMNS
+    ))
+        .bind(now.timestamp())
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.into_iter().map(todo_from_row).collect())
+}
+
+// Deleting a todo deletes its subtasks with it. Returns the subtasks deleted.
+pub async fn delete_todo(pool: &SqlitePool, id: i64, expected_version: Option<i64>) -> Result<Vec<i64>, DbError> {
+    let mut tx = Immediate::begin(pool).await?;
+    let subtasks = sqlx::query_scalar(&format!("{} SELECT id FROM tree", DESCENDANTS))
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let res = sqlx::query("DELETE FROM todos WHERE id = ? AND (? IS NULL OR version = ?)")
         .bind(id)
-        .execute(&conn().await?)
+        .bind(expected_version)
+        .bind(expected_version)
+        .execute(&mut *tx)
         .await?;
+    if res.rows_affected() == 0 {
+        drop(tx);
+        return ensure_written(pool, id, expected_version, 0).await.map(|_| Vec::new());
+    }
+    tx.commit().await?;
+    Ok(subtasks)
+}
+
+// Writes that touch no rows mean the id didn't exist
+fn ensure_found(rows_affected: u64) -> Result<(), DbError> {
+    if rows_affected == 0 {
+        return Err(DbError::NotFound(Error::RowNotFound));
+    }
     Ok(())
 }
 
-pub async fn get_todos() -> Result<Vec<Todo>, DbError> {
-    let rows: Vec<(i64, String, i8)> = sqlx::query_as("SELECT id, title, completed FROM todos ORDER BY id DESC")
-        .fetch_all(&conn().await?)
+// Like ensure_found, but a todo that exists must have moved past the expected version
+async fn ensure_written(pool: &SqlitePool, id: i64, expected_version: Option<i64>, rows_affected: u64) -> Result<(), DbError> {
+    let Some(expected) = expected_version.filter(|_| rows_affected == 0) else {
+        return ensure_found(rows_affected);
+    };
+    let current: Option<i64> = sqlx::query_scalar("SELECT version FROM todos WHERE id=?")
+        .bind(id)
+        .fetch_optional(pool)
         .await?;
-    Ok(rows
-        .iter()
-        .map(|row| Todo {
-            id: row.0,
-            title: row.1.clone(),
-            completed: row.2 == 1,
-        })
-        .collect::<Vec<Todo>>())
+    match current {
+        Some(current) => Err(DbError::VersionMismatch { current, expected }),
+        None => ensure_found(0),
+    }
 }
 
+// The top-level todos of a list that match a query, in its order, with their subtasks
+pub async fn get_todos(pool: &SqlitePool, list_id: i64, query: &TodoQuery) -> Result<Vec<Todo>, DbError> {
+    let mut sql = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM todos WHERE parent_id IS NULL AND list_id = ", TODO_COLUMNS));
+    sql.push_bind(list_id);
+
+    if let Some(completed) = query.completed {
+        sql.push(" AND completed = ").push_bind(completed as i8);
+    }
+    for tag in query.tags() {
+        sql.push(" AND EXISTS (SELECT 1 FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = todos.id AND tags.name = ")
+            .push_bind(tag)
+            .push(")");
+    }
+    if let Some(priority) = query.priority {
+        sql.push(" AND priority = ").push_bind(priority.to_db());
+    }
+    if let Some(text) = query.text() {
+        let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        sql.push(" AND title LIKE ").push_bind(pattern).push(" ESCAPE '\\'");
+    }
+    if let Some(due) = query.due {
+        let (from, until, open_only) = due.window(Utc::now(), &Local);
+        sql.push(" AND due_at >= ").push_bind(from).push(" AND due_at < ").push_bind(until);
+        if open_only {
+            sql.push(" AND completed = 0");
+        }
+    }
+
+    sql.push(" ORDER BY ").push(query.sort.order_by());
+    // SQLite only takes an OFFSET after a LIMIT; -1 is no limit
+    sql.push(" LIMIT ").push_bind(query.limit.map_or(-1, i64::from));
+    sql.push(" OFFSET ").push_bind(query.offset.map_or(0, i64::from));
+
+    let rows: Vec<TodoRow> = sql.build_query_as().fetch_all(pool).await?;
+    let mut todos: Vec<Todo> = rows.into_iter().map(todo_from_row).collect();
+    attach_subtasks(pool, &mut todos).await?;
+    Ok(todos)
+}
+
+// Database failures, classified so callers can pick a meaningful response
 #[derive(Debug)]
-pub struct DbError;
+pub enum DbError {
+    NotFound(Error),
+    Conflict(Error),
+    Constraint(Error),
+    Io(Error),
+    Other(Error),
+    // An If-Match precondition failed: the todo has moved on since
+    VersionMismatch { current: i64, expected: i64 },
+    // A subtask would sit deeper than MAX_DEPTH
+    TooDeep { parent_id: i64, depth: u32 },
+    // A move's neighbors have swapped places since the client saw them
+    OutOfOrder,
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::NotFound(_) => write!(f, "Not found"),
+            DbError::Conflict(_) => write!(f, "Conflicts with an existing record"),
+            DbError::Constraint(_) => write!(f, "Invalid data"),
+            DbError::Io(_) => write!(f, "Storage is unavailable"),
+            DbError::Other(_) => write!(f, "Unexpected database error"),
+            DbError::VersionMismatch { current, expected } => write!(f, "The todo is at version {}, not {}", current, expected),
+            DbError::TooDeep { parent_id, depth } => write!(f, "Todo {} is already {} levels deep", parent_id, depth),
+            DbError::OutOfOrder => write!(f, "The neighbors are no longer in that order"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::NotFound(e)
+            | DbError::Conflict(e)
+            | DbError::Constraint(e)
+            | DbError::Io(e)
+            | DbError::Other(e) => Some(e),
+            DbError::VersionMismatch { .. } | DbError::TooDeep { .. } | DbError::OutOfOrder => None,
+        }
+    }
+}
 
 impl From<Error> for DbError {
-    fn from(_: Error) -> Self {
-        DbError
+    fn from(e: Error) -> Self {
+        match &e {
+            Error::RowNotFound => DbError::NotFound(e),
+            Error::Database(db) => match db.kind() {
+                ErrorKind::UniqueViolation => DbError::Conflict(e),
+                ErrorKind::ForeignKeyViolation
+                | ErrorKind::NotNullViolation
+                | ErrorKind::CheckViolation => DbError::Constraint(e),
+                _ => DbError::Other(e),
+            },
+            Error::Io(_) | Error::PoolTimedOut | Error::PoolClosed => DbError::Io(e),
+            _ => DbError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn todo(pool: &SqlitePool, title: &str) -> i64 {
+        add_todo(pool, 1, &TodoDraft { title, ..Default::default() }).await.unwrap()
+    }
+
+    async fn subtask(pool: &SqlitePool, parent_id: i64, title: &str) -> i64 {
+        add_todo(pool, 1, &TodoDraft { title, parent_id: Some(parent_id), ..Default::default() }).await.unwrap()
+    }
+
+    async fn completed(pool: &SqlitePool, id: i64) -> bool {
+        get_todo(pool, id).await.unwrap().completed
+    }
+
+    #[rocket::async_test]
+    async fn edits_land_together_under_one_version() {
+        let pool = test_pool().await;
+        let id = todo(&pool, "file taxes").await;
+        let changes = TodoChanges {
+            title: Some(("file taxes by friday".to_string(), Vec::new())),
+            completed: Some(true),
+            priority: Some(Priority::High),
+            tags: Some(vec!["home".to_string()]),
+            ..Default::default()
+        };
+        edit_todo(&pool, id, &changes, Some(1)).await.unwrap();
+
+        let saved = get_todo(&pool, id).await.unwrap();
+        assert_eq!(saved.version, 2);
+        assert_eq!(saved.title, "file taxes by friday");
+        assert!(saved.completed);
+        assert_eq!(saved.priority, Priority::High);
+        assert_eq!(saved.tags, ["home"]);
+
+        // A stale version writes none of it
+        let stale = TodoChanges { completed: Some(false), tags: Some(Vec::new()), ..Default::default() };
+        assert!(matches!(edit_todo(&pool, id, &stale, Some(1)).await, Err(DbError::VersionMismatch { current: 2, expected: 1 })));
+        let unchanged = get_todo(&pool, id).await.unwrap();
+        assert!(unchanged.completed);
+        assert_eq!(unchanged.tags, ["home"]);
+    }
+
+    #[rocket::async_test]
+    async fn progress_counts_every_level() {
+        let pool = test_pool().await;
+        let trip = todo(&pool, "plan trip").await;
+        let book = subtask(&pool, trip, "book").await;
+        let flight = subtask(&pool, book, "flight").await;
+        subtask(&pool, book, "hotel").await;
+        let pack = subtask(&pool, trip, "pack").await;
+        set_todo_completed(&pool, flight, true, None).await.unwrap();
+        set_todo_completed(&pool, pack, true, None).await.unwrap();
+
+        let trip = get_todo(&pool, trip).await.unwrap();
+        assert_eq!((trip.progress.done, trip.progress.total), (2, 4));
+        let book = &trip.subtasks[0];
+        assert_eq!(book.depth, 1);
+        assert_eq!(book.subtasks[0].depth, 2);
+        assert_eq!((book.progress.done, book.progress.total), (1, 2));
+    }
+
+    #[rocket::async_test]
+    async fn completing_cascades_down_and_reopening_up() {
+        let pool = test_pool().await;
+        let trip = todo(&pool, "plan trip").await;
+        let book = subtask(&pool, trip, "book").await;
+        let flight = subtask(&pool, book, "flight").await;
+        let pack = subtask(&pool, trip, "pack").await;
+
+        let completion = set_todo_completed(&pool, trip, true, None).await.unwrap();
+        let mut cascaded = completion.cascaded.clone();
+        cascaded.sort();
+        assert_eq!(cascaded, [book, flight, pack]);
+        assert!(completed(&pool, flight).await);
+
+        // Reopening the flight reopens what it's under, and leaves its siblings' subtree be
+        let completion = set_todo_completed(&pool, flight, false, None).await.unwrap();
+        let mut cascaded = completion.cascaded.clone();
+        cascaded.sort();
+        assert_eq!(cascaded, [trip, book]);
+        assert!(!completed(&pool, trip).await);
+        assert!(completed(&pool, pack).await);
+
+        let loaded = get_todos_by_id(&pool, &cascaded).await.unwrap();
+        assert_eq!(loaded.iter().map(|todo| (todo.id, todo.depth)).collect::<Vec<_>>(), [(trip, 0), (book, 1)]);
+    }
+
+    #[rocket::async_test]
+    async fn deleting_takes_the_subtree_along() {
+        let pool = test_pool().await;
+        let trip = todo(&pool, "plan trip").await;
+        let book = subtask(&pool, trip, "book").await;
+        let flight = subtask(&pool, book, "flight").await;
+        let other = todo(&pool, "water plants").await;
+
+        let mut deleted = delete_todo(&pool, trip, None).await.unwrap();
+        deleted.sort();
+        assert_eq!(deleted, [book, flight]);
+        for id in [trip, book, flight] {
+            assert!(matches!(get_todo(&pool, id).await, Err(DbError::NotFound(_))));
+        }
+        assert!(get_todo(&pool, other).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn subtasks_stop_at_the_depth_limit() {
+        let pool = test_pool().await;
+        let mut parent = todo(&pool, "level 0").await;
+        for level in 1..MAX_DEPTH {
+            parent = subtask(&pool, parent, &format!("level {}", level)).await;
+        }
+        let draft = TodoDraft { title: "too deep", parent_id: Some(parent), ..Default::default() };
+        assert!(matches!(add_todo(&pool, 1, &draft).await, Err(DbError::TooDeep { parent_id, depth: MAX_DEPTH }) if parent_id == parent));
+    }
+
+    #[rocket::async_test]
+    async fn a_recurring_todo_repeats_once() {
+        let pool = test_pool().await;
+        let draft = TodoDraft { title: "water plants", recurrence: Some("FREQ=DAILY"), ..Default::default() };
+        let id = add_todo(&pool, 1, &draft).await.unwrap();
+
+        let first = set_todo_completed(&pool, id, true, None).await.unwrap();
+        assert!(first.next.is_some());
+        set_todo_completed(&pool, id, false, None).await.unwrap();
+        let again = set_todo_completed(&pool, id, true, None).await.unwrap();
+        assert_eq!(again.next, None);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM todos").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 2);
+
+        // The occurrence after that one is its own to add
+        let second = set_todo_completed(&pool, first.next.unwrap(), true, None).await.unwrap();
+        assert!(second.next.is_some());
+    }
+
+    // Concurrent writers need a file: WAL and locking don't apply to :memory:
+    #[rocket::async_test]
+    async fn concurrent_adds_take_turns() {
+        let path = std::env::temp_dir().join(format!("todos-test-{}.db", std::process::id()));
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new().max_connections(4).connect_with(options).await.unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+
+        let adds: Vec<_> = (0..20)
+            .map(|i| {
+                let pool = pool.clone();
+                rocket::tokio::spawn(async move {
+                    let title = format!("todo {}", i);
+                    add_todo(&pool, 1, &TodoDraft { title: &title, ..Default::default() }).await
+                })
+            })
+            .collect();
+        for add in adds {
+            add.await.unwrap().unwrap();
+        }
+        let positions: i64 = sqlx::query_scalar("SELECT COUNT(DISTINCT position) FROM todos").fetch_one(&pool).await.unwrap();
+        assert_eq!(positions, 20);
+
+        pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
     }
 }
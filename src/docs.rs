@@ -0,0 +1,192 @@
+// Machine-readable descriptions of the HTTP routes (OpenAPI) and of the
+// /todo-ws messages (AsyncAPI), both generated from the route and type
+// definitions so they can't drift from the code.
+use crate::api::{ErrorBody, NewTodo, TodoPatch};
+use crate::db::{Priority, Sort, Todo};
+use crate::presence::Presence;
+use crate::protocol::{ClientMessage, ErrorCode, ServerMessage, TodoUpdate};
+use crate::schedule::Due;
+use rocket::serde::json::{json, Json, Value};
+use rocket::Route;
+use rocket_dyn_templates::{context, Template};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+pub fn routes() -> Vec<Route> {
+    routes![get_openapi, get_asyncapi, get_docs]
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Todo", description = "Shared todo lists. The JSON API lives under /api/v1; the htmx routes return HTML fragments."),
+    paths(
+        crate::api::list_todos,
+        crate::api::get_todo_json,
+        crate::api::create_todo,
+        crate::api::patch_todo,
+        crate::api::delete_todo_json,
+        crate::get_todo_cards,
+        crate::post_todos,
+        crate::get_todo_read,
+        crate::get_todo_edit,
+        crate::post_todo_edit,
+        crate::post_todo_toggle,
+        crate::post_todo_details,
+        crate::post_todo_move,
+        crate::delete_todo_endpoint,
+        crate::sse::get_todo_events,
+        crate::sse::post_todo_events,
+        crate::post_lists,
+        crate::auth::post_login,
+        crate::auth::post_register,
+        crate::auth::post_logout,
+        crate::auth::get_farcaster_nonce,
+        crate::auth::post_farcaster,
+    ),
+    components(schemas(Todo, Priority, Sort, Due, NewTodo, TodoPatch, ErrorBody, Presence, ClientMessage, ServerMessage, ErrorCode, TodoUpdate)),
+    modifiers(&SessionCookie),
+    tags(
+        (name = "todos", description = "JSON API"),
+        (name = "htmx", description = "Form posts and HTML fragments used by the page"),
+        (name = "events", description = "Server-Sent Events fallback for /todo-ws"),
+        (name = "auth", description = "Signing in, for the page and for API clients that keep the session cookie"),
+    )
+)]
+struct ApiDoc;
+
+// Every route wants the private session cookie set by /login or /register
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("session", SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session"))));
+        }
+    }
+}
+
+#[get("/openapi.json")]
+fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// The WebSocket protocol, reusing the schemas generated for the OpenAPI document
+#[get("/asyncapi.json")]
+fn get_asyncapi() -> Json<Value> {
+    Json(asyncapi())
+}
+
+fn asyncapi() -> Value {
+    let openapi = ApiDoc::openapi();
+    let schemas = openapi.components.map(|components| components.schemas).unwrap_or_default();
+
+    json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": "Todo live updates",
+            "version": openapi.info.version,
+            "description": "JSON text frames over /todo-ws. Broadcasts carry a `seq` per list; \
+                send `resume` with the last one seen after reconnecting. With `format=htmx` \
+                the server sends HTML fragments for the htmx ws extension instead.",
+        },
+        "defaultContentType": "application/json",
+        "channels": {
+            "/todo-ws": {
+                "bindings": {
+                    "ws": {
+                        "query": {
+                            "type": "object",
+                            "properties": {
+                                "list": { "type": "string", "description": "List slug; the default list when left out" },
+                                "format": { "type": "string", "enum": ["json", "htmx"], "default": "json" },
+                            },
+                        },
+                    },
+                },
+                "publish": {
+                    "summary": "Messages a client sends",
+                    "message": {
+                        "name": "ClientMessage",
+                        "payload": { "$ref": "#/components/schemas/ClientMessage" },
+                    },
+                },
+                "subscribe": {
+                    "summary": "Broadcasts (with `seq`) and replies to this socket",
+                    "message": {
+                        "oneOf": [
+                            {
+                                "name": "TodoUpdate",
+                                "summary": "A broadcast to everyone on the list",
+                                "payload": { "$ref": "#/components/schemas/TodoUpdate" },
+                            },
+                            {
+                                "name": "ServerMessage",
+                                "summary": "A reply to this socket only",
+                                "payload": { "$ref": "#/components/schemas/ServerMessage" },
+                            },
+                        ],
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": schemas,
+        },
+    })
+}
+
+#[get("/docs")]
+fn get_docs() -> Template {
+    Template::render(
+        "api-docs",
+        context! {
+            spec_url: "/api/openapi.json",
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every schema a document refers to has to be in it
+    fn assert_refs_resolve(doc: &Value, value: &Value) {
+        match value {
+            Value::Object(fields) => {
+                if let Some(Value::String(target)) = fields.get("$ref") {
+                    let name = target.strip_prefix("#/components/schemas/").unwrap_or(target);
+                    assert!(doc["components"]["schemas"][name].is_object(), "{} doesn't resolve", target);
+                }
+                fields.values().for_each(|field| assert_refs_resolve(doc, field));
+            }
+            Value::Array(items) => items.iter().for_each(|item| assert_refs_resolve(doc, item)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn openapi_describes_the_form_and_auth_routes() {
+        let openapi = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for (path, method) in [
+            ("/api/v1/todos/{id}", "patch"),
+            ("/todos", "post"),
+            ("/lists", "post"),
+            ("/login", "post"),
+            ("/register", "post"),
+            ("/auth/farcaster", "post"),
+            ("/todo-events", "get"),
+        ] {
+            assert!(openapi["paths"][path][method].is_object(), "{} {} is missing", method, path);
+        }
+        assert_refs_resolve(&openapi, &openapi);
+    }
+
+    #[test]
+    fn asyncapi_describes_both_directions() {
+        let asyncapi = asyncapi();
+        let channel = &asyncapi["channels"]["/todo-ws"];
+        assert_eq!(channel["publish"]["message"]["payload"]["$ref"], "#/components/schemas/ClientMessage");
+        assert_eq!(channel["subscribe"]["message"]["oneOf"].as_array().map(Vec::len), Some(2));
+        assert_refs_resolve(&asyncapi, &asyncapi);
+    }
+}
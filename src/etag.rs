@@ -0,0 +1,44 @@
+// HTTP optimistic concurrency: todo versions go out as ETags and come back as If-Match
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+// A response carrying the version of the todo it shows
+#[derive(Responder)]
+pub struct Versioned<R> {
+    inner: R,
+    etag: Header<'static>,
+}
+
+impl<R> Versioned<R> {
+    pub fn new(inner: R, version: i64) -> Self {
+        Versioned {
+            inner,
+            etag: Header::new("ETag", format!("\"{}\"", version)),
+        }
+    }
+}
+
+// The version a write was based on; None when the client sent no If-Match (or `*`)
+pub struct IfMatch(pub Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(value) = req.headers().get_one("If-Match").map(str::trim) else {
+            return Outcome::Success(IfMatch(None));
+        };
+        if value == "*" {
+            return Outcome::Success(IfMatch(None));
+        }
+
+        let tag = value.trim_start_matches("W/").trim_matches('"');
+        match tag.parse::<i64>() {
+            Ok(version) => Outcome::Success(IfMatch(Some(version))),
+            // A tag we never issued can't match anything
+            Err(_) => Outcome::Error((Status::PreconditionFailed, ())),
+        }
+    }
+}
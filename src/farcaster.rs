@@ -0,0 +1,355 @@
+// Sign In With Farcaster: EIP-4361 messages signed by an FID's custody address.
+// The signer is recovered from the signature itself, but the message names its own
+// address, so who actually holds the FID comes from a FidResolver (a Farcaster hub).
+use crate::config::var;
+use chrono::{DateTime, FixedOffset, Utc};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use reqwest::StatusCode;
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+use std::time::Duration;
+
+// Farcaster's IdRegistry lives on OP Mainnet
+const CHAIN_ID: u64 = 10;
+const FID_RESOURCE: &str = "farcaster://fid/";
+
+// Hub queried when FARCASTER_HUB_URL isn't set
+const DEFAULT_HUB_URL: &str = "https://hub.pinata.cloud";
+const HUB_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The fields of a sign-in message we act on
+#[derive(Debug, Clone)]
+pub struct SignInMessage {
+    pub domain: String,
+    pub address: [u8; 20],
+    pub nonce: String,
+    pub fid: i64,
+    pub expiration_time: Option<DateTime<FixedOffset>>,
+    pub not_before: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiwfError {
+    Malformed(&'static str),
+    BadSignature,
+    WrongSigner,
+    WrongDomain,
+    WrongNonce,
+    Expired,
+    NotYetValid,
+    // The FID's owner couldn't be looked up
+    Unresolved(String),
+}
+
+impl std::fmt::Display for SiwfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SiwfError::Malformed(what) => write!(f, "Malformed sign-in message: {}", what),
+            SiwfError::BadSignature => write!(f, "Invalid signature"),
+            SiwfError::WrongSigner => write!(f, "Signature is not from the custody address"),
+            SiwfError::WrongDomain => write!(f, "Message was issued for another domain"),
+            SiwfError::WrongNonce => write!(f, "Message nonce does not match"),
+            SiwfError::Expired => write!(f, "Message has expired"),
+            SiwfError::NotYetValid => write!(f, "Message is not valid yet"),
+            SiwfError::Unresolved(reason) => write!(f, "Could not look up the FID: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SiwfError {}
+
+impl SignInMessage {
+    pub fn parse(message: &str) -> Result<Self, SiwfError> {
+        let mut lines = message.lines();
+        let domain = lines
+            .next()
+            .and_then(|line| line.strip_suffix(" wants you to sign in with your Ethereum account:"))
+            .ok_or(SiwfError::Malformed("header"))?
+            .to_string();
+        let address = lines
+            .next()
+            .and_then(|line| parse_address(line.trim()))
+            .ok_or(SiwfError::Malformed("address"))?;
+
+        let mut version = None;
+        let mut chain_id = None;
+        let mut nonce = None;
+        let mut expiration_time = None;
+        let mut not_before = None;
+        let mut fid = None;
+        for line in lines {
+            if let Some(resource) = line.strip_prefix("- ") {
+                if let Some(id) = resource.strip_prefix(FID_RESOURCE) {
+                    fid = Some(id.parse::<i64>().map_err(|_| SiwfError::Malformed("fid"))?);
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "Version" => version = Some(value),
+                "Chain ID" => chain_id = value.parse::<u64>().ok(),
+                "Nonce" => nonce = Some(value.to_string()),
+                "Expiration Time" => expiration_time = Some(parse_time(value, "expiration time")?),
+                "Not Before" => not_before = Some(parse_time(value, "not before")?),
+                _ => {}
+            }
+        }
+
+        if version != Some("1") {
+            return Err(SiwfError::Malformed("version"));
+        }
+        if chain_id != Some(CHAIN_ID) {
+            return Err(SiwfError::Malformed("chain id"));
+        }
+        Ok(SignInMessage {
+            domain,
+            address,
+            nonce: nonce.ok_or(SiwfError::Malformed("nonce"))?,
+            fid: fid.ok_or(SiwfError::Malformed("fid resource"))?,
+            expiration_time,
+            not_before,
+        })
+    }
+}
+
+// Who holds an FID, according to the Farcaster registries
+#[rocket::async_trait]
+pub trait FidResolver: Send + Sync {
+    // The address in the IdRegistry that owns the FID
+    async fn custody_address(&self, fid: i64) -> Result<[u8; 20], SiwfError>;
+    // The username the FID has set for itself, if any
+    async fn fname(&self, fid: i64) -> Result<Option<String>, SiwfError>;
+}
+
+// Looks FIDs up through a hub's HTTP API
+pub struct HubResolver {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HubResolver {
+    pub fn from_env() -> Self {
+        let url = var("FARCASTER_HUB_URL", DEFAULT_HUB_URL.to_string());
+        HubResolver {
+            client: reqwest::Client::builder().timeout(HUB_TIMEOUT).build().unwrap_or_default(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // None when the hub has nothing for the FID, which it answers with a 404
+    async fn get(&self, path: &str, fid: i64, query: &[(&str, &str)]) -> Result<Option<Value>, SiwfError> {
+        let unresolved = |e: reqwest::Error| SiwfError::Unresolved(e.to_string());
+        let response = self.client
+            .get(format!("{}{}", self.url, path))
+            .query(&[("fid", fid.to_string())])
+            .query(query)
+            .send()
+            .await
+            .map_err(unresolved)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let value = response.error_for_status().map_err(unresolved)?.json().await.map_err(unresolved)?;
+        Ok(Some(value))
+    }
+}
+
+#[rocket::async_trait]
+impl FidResolver for HubResolver {
+    async fn custody_address(&self, fid: i64) -> Result<[u8; 20], SiwfError> {
+        // The latest register or transfer event names the current owner
+        let event = self.get("/v1/onChainIdRegistryEventByFid", fid, &[]).await?.unwrap_or_default();
+        event["idRegisterEventBody"]["to"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or_else(|| SiwfError::Unresolved(format!("no custody address for fid {}", fid)))
+    }
+
+    async fn fname(&self, fid: i64) -> Result<Option<String>, SiwfError> {
+        // Nothing for FIDs that never set a username
+        let Some(data) = self.get("/v1/userDataByFid", fid, &[("user_data_type", "USER_DATA_TYPE_USERNAME")]).await? else {
+            return Ok(None);
+        };
+        Ok(data["data"]["userDataBody"]["value"]
+            .as_str()
+            .map(str::trim)
+            .filter(|name| (1..=64).contains(&name.len()))
+            .map(str::to_string))
+    }
+}
+
+// Check a signed sign-in message against what this server issued, and that the
+// signer holds the FID it signs in as
+pub async fn verify_sign_in(
+    message: &str,
+    signature: &str,
+    domain: &str,
+    nonce: &str,
+    now: DateTime<Utc>,
+    resolver: &dyn FidResolver,
+) -> Result<SignInMessage, SiwfError> {
+    let parsed = SignInMessage::parse(message)?;
+    let signer = recover_address(message.as_bytes(), signature)?;
+    if signer != parsed.address {
+        return Err(SiwfError::WrongSigner);
+    }
+    if parsed.domain != domain {
+        return Err(SiwfError::WrongDomain);
+    }
+    if parsed.nonce != nonce {
+        return Err(SiwfError::WrongNonce);
+    }
+    if parsed.expiration_time.is_some_and(|t| now >= t) {
+        return Err(SiwfError::Expired);
+    }
+    if parsed.not_before.is_some_and(|t| now < t) {
+        return Err(SiwfError::NotYetValid);
+    }
+    // Anyone can sign a message naming their own address and any FID
+    if resolver.custody_address(parsed.fid).await? != signer {
+        return Err(SiwfError::WrongSigner);
+    }
+    Ok(parsed)
+}
+
+// Address that produced an EIP-191 `personal_sign` signature over `message`
+pub fn recover_address(message: &[u8], signature: &str) -> Result<[u8; 20], SiwfError> {
+    let bytes = hex::decode(signature.trim_start_matches("0x")).map_err(|_| SiwfError::BadSignature)?;
+    if bytes.len() != 65 {
+        return Err(SiwfError::BadSignature);
+    }
+    let signature = Signature::from_slice(&bytes[..64]).map_err(|_| SiwfError::BadSignature)?;
+    // Wallets send v as 27/28, some libraries as 0/1
+    let v = match bytes[64] {
+        27 | 28 => bytes[64] - 27,
+        v => v,
+    };
+    let recovery_id = RecoveryId::from_byte(v).ok_or(SiwfError::BadSignature)?;
+
+    let mut hasher = Keccak256::new();
+    hasher.update(format!("\x19Ethereum Signed Message:\n{}", message.len()));
+    hasher.update(message);
+    let key = VerifyingKey::recover_from_prehash(&hasher.finalize(), &signature, recovery_id)
+        .map_err(|_| SiwfError::BadSignature)?;
+
+    // The address is the last 20 bytes of the hashed uncompressed key, minus its 0x04 tag
+    let point = key.to_encoded_point(false);
+    let hash = Keccak256::digest(&point.as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    Ok(address)
+}
+
+fn parse_address(text: &str) -> Option<[u8; 20]> {
+    let bytes = hex::decode(text.strip_prefix("0x")?).ok()?;
+    bytes.try_into().ok()
+}
+
+fn parse_time(value: &str, field: &'static str) -> Result<DateTime<FixedOffset>, SiwfError> {
+    DateTime::parse_from_rfc3339(value).map_err(|_| SiwfError::Malformed(field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Signed with the well-known Hardhat test key #0
+    const MESSAGE: &str = "localhost:8123 wants you to sign in with your Ethereum account:\n0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266\n\nFarcaster Auth\n\nURI: http://localhost:8123/login\nVersion: 1\nChain ID: 10\nNonce: Zq4uVfP1nR8sKx2T\nIssued At: 2025-01-01T00:00:00.000Z\nExpiration Time: 2025-01-01T00:10:00.000Z\nResources:\n- farcaster://fid/20721";
+    const SIGNATURE: &str = "0x8522b7ce48e55b929f1bcaa7f0287c6cc0ebfe9e938e6f5ef28a2a50101ae2cb24e95754bb6aebdff6b721aa72b9572442774bcd491aebbc8e2c966d6be7634b1c";
+    const SIGNER: &str = "f39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    // A registry where every FID belongs to one address
+    struct Registry(&'static str);
+
+    #[rocket::async_trait]
+    impl FidResolver for Registry {
+        async fn custody_address(&self, _fid: i64) -> Result<[u8; 20], SiwfError> {
+            Ok(parse_address(&format!("0x{}", self.0)).unwrap())
+        }
+
+        async fn fname(&self, _fid: i64) -> Result<Option<String>, SiwfError> {
+            Ok(None)
+        }
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    fn verify(message: &str, nonce: &str, now: &str) -> Result<SignInMessage, SiwfError> {
+        verify_with(message, "localhost:8123", nonce, now, &Registry(SIGNER))
+    }
+
+    fn verify_with(message: &str, domain: &str, nonce: &str, now: &str, registry: &Registry) -> Result<SignInMessage, SiwfError> {
+        rocket::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(verify_sign_in(message, SIGNATURE, domain, nonce, at(now), registry))
+    }
+
+    #[test]
+    fn accepts_fixture_signature() {
+        let message = verify(MESSAGE, "Zq4uVfP1nR8sKx2T", "2025-01-01T00:05:00Z").unwrap();
+        assert_eq!(message.fid, 20721);
+        assert_eq!(hex::encode(message.address), SIGNER);
+    }
+
+    #[test]
+    fn rejects_signer_without_custody() {
+        // A valid signature, but the FID belongs to someone else
+        let registry = Registry("2fdef3f470ecd2bc9a977568c43ad3860c1611d8");
+        let err = verify_with(MESSAGE, "localhost:8123", "Zq4uVfP1nR8sKx2T", "2025-01-01T00:05:00Z", &registry);
+        assert_eq!(err.unwrap_err(), SiwfError::WrongSigner);
+    }
+
+    #[test]
+    fn recovers_manifest_custody_address() {
+        // accountAssociation from static/.well-known/farcaster.json, signed over `header.payload`
+        let signed = "eyJmaWQiOjM1MDkxMSwidHlwZSI6ImN1c3RvZHkiLCJrZXkiOiIweDJGREVmM0Y0NzBlQ2QyQmM5YTk3NzU2OEM0M0FEMzg2MGMxNjExRDgifQ.eyJkb21haW4iOiJydXN0LXdzLXByb2R1Y3Rpb24udXAucmFpbHdheS5hcHAifQ";
+        let signature = "0xce4ea56cfea6c03e2d8d321230c006201e94aa5c6b350e3552dbab1b495d61195cafc4c2072c6769b4c6ebf26681f055235b71085731544d5a9e9a7f9b88c9181b";
+        let address = recover_address(signed.as_bytes(), signature).unwrap();
+        assert_eq!(hex::encode(address), "2fdef3f470ecd2bc9a977568c43ad3860c1611d8");
+    }
+
+    #[test]
+    fn rejects_tampered_message() {
+        let tampered = MESSAGE.replace("fid/20721", "fid/1");
+        assert_eq!(verify(&tampered, "Zq4uVfP1nR8sKx2T", "2025-01-01T00:05:00Z").unwrap_err(), SiwfError::WrongSigner);
+    }
+
+    #[test]
+    fn rejects_wrong_nonce_and_domain() {
+        assert_eq!(verify(MESSAGE, "other-nonce", "2025-01-01T00:05:00Z").unwrap_err(), SiwfError::WrongNonce);
+        let err = verify_with(MESSAGE, "example.com", "Zq4uVfP1nR8sKx2T", "2025-01-01T00:05:00Z", &Registry(SIGNER));
+        assert_eq!(err.unwrap_err(), SiwfError::WrongDomain);
+    }
+
+    #[test]
+    fn rejects_expired_message() {
+        assert_eq!(verify(MESSAGE, "Zq4uVfP1nR8sKx2T", "2025-01-01T00:10:00Z").unwrap_err(), SiwfError::Expired);
+    }
+
+    // A hub that gives every request the same status and an empty JSON object
+    fn hub(status: &'static str) -> HubResolver {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 4096]);
+                let _ = write!(stream, "HTTP/1.1 {}\r\ncontent-length: 2\r\nconnection: close\r\n\r\n{{}}", status);
+            }
+        });
+        HubResolver { client: reqwest::Client::new(), url }
+    }
+
+    #[rocket::async_test]
+    async fn only_a_404_means_no_fname() {
+        // FID 404 puts "404" in the URL, and so in any error about it
+        assert_eq!(hub("404 Not Found").fname(404).await, Ok(None));
+        assert!(matches!(hub("500 Internal Server Error").fname(404).await, Err(SiwfError::Unresolved(_))));
+        assert_eq!(hub("200 OK").fname(404).await, Ok(None));
+    }
+}
@@ -0,0 +1,72 @@
+// Server-side keepalive for WebSockets. A client that disappears without closing
+// (a phone backgrounding the app) never sends a Close frame, so we ping it and
+// drop the connection once it stops answering.
+use crate::config::var;
+use rocket::tokio::time::Instant;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    // Quiet time after which the server sends a Ping
+    pub ping_interval: Duration,
+    // How long a Ping may go unanswered before the connection is dropped
+    pub pong_timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    pub fn from_env() -> Self {
+        HeartbeatConfig {
+            ping_interval: Duration::from_secs(var("WS_PING_INTERVAL_SECS", 20)),
+            pong_timeout: Duration::from_secs(var("WS_PONG_TIMEOUT_SECS", 10)),
+        }
+    }
+}
+
+pub enum Beat {
+    // Time to send a Ping
+    Ping,
+    // The last Ping went unanswered
+    TimedOut,
+}
+
+// Liveness of one connection
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    last_seen: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Heartbeat {
+            config,
+            last_seen: Instant::now(),
+            ping_sent: None,
+        }
+    }
+
+    // Any frame from the client, pong or not, shows it is still there
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+        self.ping_sent = None;
+    }
+
+    // When `beat` next has something to do
+    pub fn deadline(&self) -> Instant {
+        match self.ping_sent {
+            Some(sent) => sent + self.config.pong_timeout,
+            None => self.last_seen + self.config.ping_interval,
+        }
+    }
+
+    // Call once `deadline` has passed
+    pub fn beat(&mut self) -> Beat {
+        match self.ping_sent {
+            Some(_) => Beat::TimedOut,
+            None => {
+                self.ping_sent = Some(Instant::now());
+                Beat::Ping
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Publishing side of the live feed: the list rooms, plus the templates used to
+// render a changed todo once for every client instead of each fetching it
+use crate::broadcast::{Broadcaster, Rooms};
+use crate::db::{Todo, DEEPEST_PARENT};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use rocket_dyn_templates::{context, Metadata};
+use std::sync::Arc;
+
+pub struct Live<'r> {
+    rooms: &'r Rooms,
+    templates: Metadata<'r>,
+}
+
+impl Live<'_> {
+    // The broadcaster for a list
+    pub fn room(&self, list_id: i64) -> Arc<Broadcaster> {
+        self.rooms.get(list_id)
+    }
+
+    // The same `todo-read` card `/todo-read/<id>` serves
+    pub fn card(&self, todo: &Todo) -> Option<String> {
+        self.render("todo-read", context! { todo, deepest_parent: DEEPEST_PARENT })
+    }
+
+    // Every card of a list, as `/todo-cards` serves them
+    pub fn cards(&self, todos: &[Todo]) -> Option<String> {
+        self.render("todo-cards", context! { todos, deepest_parent: DEEPEST_PARENT })
+    }
+
+    fn render(&self, name: &'static str, context: impl serde::Serialize) -> Option<String> {
+        let rendered = self.templates.render(name, context).map(|(_, html)| html);
+        if rendered.is_none() {
+            println!("Failed to render {} for broadcast", name);
+        }
+        rendered
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Live<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(rooms) = req.rocket().state::<Rooms>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        req.guard::<Metadata<'r>>()
+            .await
+            .map(|templates| Live { rooms, templates })
+    }
+}
@@ -2,78 +2,72 @@
 extern crate rocket;
 
 use rocket::form::Form;
-use rocket::http::{Cookie, CookieJar, Status, Header};
-use rocket::tokio::sync::broadcast::{channel, Sender};
+use rocket::http::{Status, Header};
+use rocket::tokio::sync::broadcast::error::RecvError;
 use rocket::{State, Request, Response};
+use rocket::response::{self, Redirect, Responder};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket_dyn_templates::{context, Template};
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use rocket_ws::{WebSocket, Message, Channel};
-use rocket::serde::json::json;
+use rocket_ws::stream::DuplexStream;
+use rocket_ws::frame::{CloseCode, CloseFrame};
 use futures_util::{SinkExt, StreamExt};
 use rocket::fs::{FileServer, relative};
 
-use crate::db::{add_todo, delete_todo, DbError, get_todo, get_todos, maybe_create_database, update_todo};
+use crate::db::{add_list, add_todo, Completion, create_pool, delete_todo, DbConfig, DbError, DEEPEST_PARENT, edit_todo, get_list, get_lists, get_todo, get_todos, get_todos_by_id, List, move_todo, parse_tags, Priority, root_todo_id, run_migrations, set_todo_completed, Sort, Todo, TodoChanges, TodoDraft, TodoQuery, User};
+use sqlx::SqlitePool;
+use crate::broadcast::{Broadcaster, Rooms};
+use crate::crdt::{TitleDocs, TitleError};
+use crate::etag::{IfMatch, Versioned};
+use crate::heartbeat::{Beat, Heartbeat, HeartbeatConfig};
+use crate::live::Live;
+use crate::presence::{ClientSessions, CursorThrottle, EditRefused, Presence, SessionGuard};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crate::protocol::{ClientMessage, ErrorCode, ServerMessage};
+use crate::recurrence::Repeat;
+use crate::schedule::{send_reminders, When};
 use serde::Serialize;
 
+mod api;
+mod auth;
+mod broadcast;
+mod config;
+mod crdt;
+mod csrf;
 mod db;
+mod docs;
+mod etag;
+mod farcaster;
+mod heartbeat;
+mod live;
+mod presence;
+mod protocol;
+mod rank;
+mod recurrence;
+mod schedule;
+mod sse;
 
 const DB_URL: &str = "sqlite://sqlite.db";
 
+// List shown at `/` and used when no list is given
+const DEFAULT_LIST: &str = "default";
+
 // Channel capacity for the todo updates
 const CHANNEL_CAPACITY: usize = 1024;
 
-// Message struct for broadcasting updates
-#[derive(Debug, Clone, Serialize)]
-struct TodoUpdate {
-    event: String,
-    todo_id: Option<i64>,
-    source_id: Option<String>,
-    content: Option<String>,  // For real-time editing updates
-    connected_users: Option<usize>, // For online user count
-}
+// Number of recent todo events kept for replaying to reconnecting clients
+const HISTORY_CAPACITY: usize = 1024;
 
-// Track client sessions
-#[derive(Default)]
-struct ClientSessions(Arc<Mutex<HashSet<String>>>);
+// How long an edit lock lasts without activity from its holder
+const EDIT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
 
-impl ClientSessions {
-    // Add a client and return the new count
-    fn add_client(&self, client_id: &str) -> usize {
-        let mut sessions = self.0.lock().unwrap();
-        
-        // Log if client already exists (shouldn't happen normally)
-        if sessions.contains(client_id) {
-            println!("WARNING: Client {} already exists in sessions", client_id);
-        }
-        
-        sessions.insert(client_id.to_string());
-        sessions.len()
-    }
-    
-    // Remove a client and return the new count
-    fn remove_client(&self, client_id: &str) -> usize {
-        let mut sessions = self.0.lock().unwrap();
-        
-        // Log if we're trying to remove a non-existent client
-        if !sessions.contains(client_id) {
-            println!("WARNING: Trying to remove non-existent client {}", client_id);
-        }
-        
-        sessions.remove(client_id);
-        sessions.len()
-    }
-    
-    // Debug print all sessions
-    fn debug_print(&self) {
-        let sessions = self.0.lock().unwrap();
-        println!("DEBUG: Current sessions ({}):", sessions.len());
-        for session in sessions.iter() {
-            println!("  - {}", session);
-        }
-    }
-}
+// Minimum gap between relayed cursor positions from one connection
+const CURSOR_INTERVAL: Duration = Duration::from_millis(50);
+
+// How often to look for reminders that have come due
+const REMINDER_INTERVAL: Duration = Duration::from_secs(15);
 
 // Custom fairing to set headers for iframe embedding
 pub struct FrameHeaders;
@@ -101,70 +95,236 @@ impl Fairing for FrameHeaders {
 
 #[rocket::main]
 async fn main() -> Result<(), Box<rocket::Error>> {
-    maybe_create_database().await.expect("Failed to create DB");
+    let pool = create_pool(&DbConfig::from_env()).await.expect("Failed to open DB pool");
+    run_migrations(&pool).await.expect("Failed to migrate DB");
 
-    let sessions = ClientSessions::default();
+    let sessions = ClientSessions::new(EDIT_LOCK_TIMEOUT, HeartbeatConfig::from_env());
+
+    let rooms = Rooms::new(CHANNEL_CAPACITY, HISTORY_CAPACITY);
+    rocket::tokio::spawn(send_reminders(pool.clone(), rooms.clone(), REMINDER_INTERVAL));
 
     let _rocket = rocket::build()
         .attach(Template::fairing())
         .attach(FrameHeaders)
-        .manage(channel::<TodoUpdate>(CHANNEL_CAPACITY).0)
+        .attach(csrf::SameOrigin::from_env())
+        .manage(rooms)
+        .manage(TitleDocs::default())
         .manage(sessions)
+        .manage(pool)
+        .manage(Box::new(farcaster::HubResolver::from_env()) as Box<dyn farcaster::FidResolver>)
         .mount(
             "/",
             routes![
                 get_index,
+                get_index_signed_out,
+                get_list_page,
+                get_list_page_signed_out,
+                post_lists,
+                get_todo_cards,
                 post_todos,
                 get_todo_read,
                 get_todo_edit,
                 post_todo_edit,
+                post_todo_toggle,
+                post_todo_details,
+                post_todo_move,
                 delete_todo_endpoint,
                 todo_websocket
             ],
         )
+        .mount("/", auth::routes())
+        .mount("/", csrf::routes())
+        .mount("/", sse::routes())
+        .mount(api::BASE, api::routes())
+        .mount("/api", docs::routes())
+        .register("/api", api::catchers())
         .mount("/.well-known", FileServer::from(relative!("static/.well-known")))
         .launch()
         .await?;
     Ok(())
 }
 
-// Get or create a unique client ID
-fn get_client_id(cookies: &CookieJar<'_>, _sessions: &State<ClientSessions>) -> String {
-    // Check if client already has an ID
-    if let Some(cookie) = cookies.get("client_id") {
-        return cookie.value().to_string();
-    }
-    
-    // Generate a new ID
-    let new_id = format!("client_{}", rand::random::<u64>());
-    
-    // Just log the new ID creation, but don't add to active sessions here
-    // (This will happen via WebSocket connection)
-    println!("New client ID created: {}", new_id);
-    
-    // Set cookie
-    cookies.add(Cookie::new("client_id", new_id.clone()));
-    
-    new_id
+// Look up a list by slug, falling back to the default list
+async fn resolve_list(pool: &SqlitePool, slug: Option<&str>) -> Result<List, DbError> {
+    get_list(pool, slug.unwrap_or(DEFAULT_LIST)).await
 }
 
-#[get("/")]
-async fn get_index(cookies: &CookieJar<'_>, sessions: &State<ClientSessions>) -> Result<Template, Status> {
-    // Ensure client has an ID
-    let _client_id = get_client_id(cookies, sessions);
+// Render the full page for one list, showing the todos that match the query
+async fn render_list_page(user: &User, pool: &SqlitePool, rooms: &Rooms, slug: &str, query: &TodoQuery) -> Result<Template, DbError> {
+    let list = get_list(pool, slug).await?;
+    let queue = rooms.get(list.id);
     
-    let todos = get_todos().await?;
+    // The page resumes the live feed from here, so read it before the todos
+    let seq = queue.current_seq();
+    let todos = get_todos(pool, list.id, query).await?;
+    let lists = get_lists(pool).await?;
     Ok(Template::render(
         "index",
         context! {
-            todos
+            user,
+            source_id: user.source_id(),
+            display_name: user.display_name(),
+            list,
+            lists,
+            todos,
+            deepest_parent: DEEPEST_PARENT,
+            query,
+            filtered: query.is_filtered(),
+            manual_order: query.sort == Sort::Manual,
+            tags: query.tags.join(", "),
+            seq,
+            epoch: queue.epoch()
         },
     ))
 }
 
+#[get("/?<query..>")]
+async fn get_index(user: User, pool: &State<SqlitePool>, rooms: &State<Rooms>, query: TodoQuery) -> Result<Template, DbError> {
+    render_list_page(&user, pool, rooms, DEFAULT_LIST, &query).await
+}
+
+#[get("/lists/<slug>?<query..>")]
+async fn get_list_page(user: User, pool: &State<SqlitePool>, rooms: &State<Rooms>, slug: &str, query: TodoQuery) -> Result<Template, DbError> {
+    render_list_page(&user, pool, rooms, slug, &query).await
+}
+
+// Pages for visitors who aren't signed in yet
+#[get("/", rank = 2)]
+fn get_index_signed_out() -> Redirect {
+    Redirect::to("/login")
+}
+
+#[get("/lists/<_>", rank = 2)]
+fn get_list_page_signed_out() -> Redirect {
+    Redirect::to("/login")
+}
+
+#[derive(FromForm, utoipa::ToSchema)]
+struct ListForm {
+    #[field(validate = len(1..=128))]
+    name: String,
+}
+
+// Turn a list name into a URL-safe slug
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-').to_string();
+    if slug.is_empty() {
+        format!("list-{}", rand::random::<u32>())
+    } else {
+        slug
+    }
+}
+
+#[utoipa::path(
+    tag = "htmx",
+    request_body(content = ListForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Redirects to the new list's page"),
+        (status = 422, description = "A name that's empty or longer than 128 characters"),
+    ),
+    security(("session" = []))
+)]
+#[post("/lists", data = "<form>")]
+async fn post_lists(_user: User, pool: &State<SqlitePool>, form: Form<ListForm>) -> Result<Redirect, DbError> {
+    let slug = slugify(&form.name);
+    add_list(pool, &slug, form.name.trim()).await?;
+    println!("✅ Created new list: {}", slug);
+    // Slugs are URL-safe already
+    Ok(Redirect::to(format!("/lists/{}", slug)))
+}
+
+// Just the list of cards, used by clients to resync after missing events
+#[utoipa::path(
+    tag = "htmx",
+    params(
+        ("list" = Option<String>, Query, description = "List slug; the default list when left out"),
+        TodoQuery,
+    ),
+    responses(
+        (status = 200, description = "The cards of the list that match the query", content_type = "text/html", body = String),
+        (status = 404, description = "No such list"),
+    ),
+    security(("session" = []))
+)]
+#[get("/todo-cards?<list>&<query..>")]
+async fn get_todo_cards(_user: User, pool: &State<SqlitePool>, list: Option<&str>, query: TodoQuery) -> Result<Template, DbError> {
+    let list = resolve_list(pool, list).await?;
+    let todos = get_todos(pool, list.id, &query).await?;
+    Ok(Template::render(
+        "todo-cards",
+        context! {
+            todos,
+            deepest_parent: DEEPEST_PARENT
+        },
+    ))
+}
+
+// Serialize and send a single message to this socket
+async fn send_message(stream: &mut DuplexStream, msg: &impl Serialize) -> Result<(), rocket_ws::result::Error> {
+    match serde_json::to_string(msg) {
+        Ok(json_str) => stream.send(Message::Text(json_str)).await,
+        Err(e) => {
+            println!("Failed to serialize WebSocket message: {}", e);
+            Ok(())
+        }
+    }
+}
+
+// Where replies to one client's messages go: its WebSocket, or the body of an HTTP response
+trait Replies {
+    async fn reply(&mut self, msg: &impl Serialize) -> Result<(), rocket_ws::result::Error>;
+}
+
+impl Replies for DuplexStream {
+    async fn reply(&mut self, msg: &impl Serialize) -> Result<(), rocket_ws::result::Error> {
+        send_message(self, msg).await
+    }
+}
+
+impl Replies for Vec<serde_json::Value> {
+    async fn reply(&mut self, msg: &impl Serialize) -> Result<(), rocket_ws::result::Error> {
+        match serde_json::to_value(msg) {
+            Ok(value) => self.push(value),
+            Err(e) => println!("Failed to serialize reply: {}", e),
+        }
+        Ok(())
+    }
+}
+
+// What a socket receives: JSON events, or rendered fragments for the htmx `ws` extension
+#[derive(FromFormField, Clone, Copy, PartialEq, Eq)]
+enum SocketFormat {
+    Json,
+    Htmx,
+}
+
+#[derive(FromForm)]
+struct SocketOptions<'r> {
+    list: Option<&'r str>,
+    #[field(default = SocketFormat::Json)]
+    format: SocketFormat,
+}
+
 // WebSocket endpoint for real-time updates
-#[get("/todo-ws")]
-fn todo_websocket<'r>(ws: WebSocket, queue: &'r State<Sender<TodoUpdate>>, sessions: &'r State<ClientSessions>) -> Channel<'r> {
+#[get("/todo-ws?<options..>")]
+async fn todo_websocket<'r>(ws: WebSocket, user: User, live: Live<'r>, sessions: &'r State<ClientSessions>, pool: &'r State<SqlitePool>, titles: &'r State<TitleDocs>, options: SocketOptions<'_>) -> Result<Channel<'r>, DbError> {
+    // Each list is its own room with its own events and presence
+    let list = resolve_list(pool, options.list).await?;
+    let list_id = list.id;
+    let queue = live.room(list_id);
+    let format = options.format;
+    
+    // Broadcasts caused by this socket are attributed to the signed-in user
+    let source_id = user.source_id();
+    
     // Generate a random client ID for this WebSocket connection
     let ws_client_id = format!("ws_client_{}", rand::random::<u64>());
     
@@ -175,35 +335,63 @@ fn todo_websocket<'r>(ws: WebSocket, queue: &'r State<Sender<TodoUpdate>>, sessi
     let mut rx = queue.subscribe();
     
     // Create the WebSocket channel
-    ws.channel(move |mut stream| {
+    Ok(ws.channel(move |mut stream| {
         Box::pin(async move {
+            let queue = &*queue;
+            
             // Add this client to active sessions and get the updated count
-            let connected_users = sessions.add_client(&ws_client_id);
-            println!("New WebSocket connection: {}. Total connected users: {}", ws_client_id, connected_users);
+            let count = sessions.add_client(list_id, Presence::new(ws_client_id.clone(), &user));
+            println!("New WebSocket connection: {} for {} on list {}. Connected users: {} ({} connections)", ws_client_id, source_id, list.slug, count.people, count.connections);
+            
+            // Removes the session again however this handler exits
+            let _session = SessionGuard {
+                sessions,
+                titles,
+                queue,
+                list_id,
+                ws_client_id: ws_client_id.clone(),
+            };
             
             // Debug print sessions again
             sessions.debug_print();
             
+            let socket = SocketContext {
+                connection_id: &ws_client_id,
+                source_id: &source_id,
+                list_id,
+                queue,
+                live: &live,
+                pool,
+                titles,
+                sessions,
+            };
+            let mut cursor = CursorThrottle::new(CURSOR_INTERVAL);
+            let mut heartbeat = Heartbeat::new(sessions.heartbeat());
+            
+            // Read the seq before the snapshot so nothing published in between is lost;
+            // broadcasts at or below this seq are skipped as already sent
+            let mut last_sent_seq = queue.current_seq();
+            
             // Broadcast user count to all clients
-            let _ = queue.send(TodoUpdate {
-                event: "user_count".to_string(),
-                todo_id: None,
-                source_id: None,
-                content: None,
-                connected_users: Some(connected_users),
-            });
+            queue.publish(ServerMessage::UserCount { connected_users: count.people, connections: count.connections }, None);
             
-            // First, try to send the initial list of todos
-            if let Ok(todos) = get_todos().await {
-                let initial_msg = json!({
-                    "event": "init",
-                    "todos": todos,
-                    "connected_users": connected_users
-                });
-                
-                if let Ok(json_str) = serde_json::to_string(&initial_msg) {
-                    let _ = stream.send(Message::Text(json_str)).await;
+            // htmx clients get the rendered list and count instead of init, and only listen from then on
+            if format == SocketFormat::Htmx {
+                let _ = send_cards(&mut stream, &live, pool, list_id).await;
+                if let Some(html) = (ServerMessage::UserCount { connected_users: count.people, connections: count.connections }).htmx() {
+                    let _ = stream.send(Message::Text(html)).await;
                 }
+            } else if let Ok(todos) = get_todos(pool, list_id, &TodoQuery::default()).await {
+                // First, try to send the initial list of todos
+                let _ = send_message(&mut stream, &ServerMessage::Init {
+                    connection_id: ws_client_id.clone(),
+                    todos: Some(todos),
+                    connected_users: count.people,
+                    connections: count.connections,
+                    presence: sessions.snapshot(list_id),
+                    seq: last_sent_seq,
+                    epoch: queue.epoch(),
+                }).await;
             }
             
             // Create a loop to handle both WebSocket messages and broadcast channel messages
@@ -211,153 +399,110 @@ fn todo_websocket<'r>(ws: WebSocket, queue: &'r State<Sender<TodoUpdate>>, sessi
                 rocket::tokio::select! {
                     // Handle broadcasts from the queue
                     msg = rx.recv() => {
-                        if let Ok(update) = msg {
-                            // Skip messages from this client by checking source_id
-                            if let Some(source_id) = &update.source_id {
-                                // Debugging to see what's happening
-                                println!("WS received update: {:?}, ws_client_id: {}", update, ws_client_id);
-                                
-                                // Match our WebSocket client ID with the cookieJar client ID
-                                if source_id.starts_with("client_") {
-                                    // Extract the cookie's value and send it to client for verification
-                                    let info_msg = json!({
-                                        "event": "debug_info",
-                                        "your_ws_id": ws_client_id,
-                                        "source_id": source_id,
-                                    });
-                                    if let Ok(info_str) = serde_json::to_string(&info_msg) {
-                                        let _ = stream.send(Message::Text(info_str)).await;
-                                    }
+                        match msg {
+                            Ok(update) => {
+                                // Already delivered by the snapshot or a resume replay
+                                if update.seq <= last_sent_seq {
+                                    continue;
                                 }
+                                last_sent_seq = update.seq;
+                        
+                                let sent = match format {
+                                    // Just forward the JSON representation of the update
+                                    SocketFormat::Json => send_message(&mut stream, &update).await,
+                                    SocketFormat::Htmx => match update.message.htmx() {
+                                        Some(html) => stream.send(Message::Text(html)).await,
+                                        None => Ok(()),
+                                    },
+                                };
+                                if sent.is_err() {
+                                    break;
+                                }
+                            },
+                            Err(RecvError::Lagged(skipped)) if format == SocketFormat::Htmx => {
+                                // Nothing to replay into; swap in the whole list again
+                                println!("WebSocket {} lagged by {} messages, resending cards", ws_client_id, skipped);
+                                last_sent_seq = queue.current_seq();
+                                if send_cards(&mut stream, &live, pool, list_id).await.is_err() {
+                                    break;
+                                }
+                            },
+                            Err(RecvError::Lagged(skipped)) => {
+                                // Too slow to keep up; catch up from history or a fresh snapshot
+                                println!("WebSocket {} lagged by {} messages, resyncing", ws_client_id, skipped);
+                                match resume(&mut stream, queue, pool, list_id, queue.epoch(), last_sent_seq).await {
+                                    Ok(seq) => last_sent_seq = last_sent_seq.max(seq),
+                                    Err(_) => break,
+                                }
+                            },
+                            Err(RecvError::Closed) => {
+                                println!("Broadcast channel closed, ending WebSocket {}", ws_client_id);
+                                break;
                             }
-                            
-                            // Just forward the JSON representation of the update
-                            if let Ok(json_str) = serde_json::to_string(&update) {
-                                if stream.send(Message::Text(json_str)).await.is_err() {
+                        }
+                    },
+                    
+                    // Send a cursor position held back by the throttle
+                    _ = rocket::tokio::time::sleep_until(cursor.deadline().unwrap_or_else(rocket::tokio::time::Instant::now)), if cursor.deadline().is_some() => {
+                        if let Some(position) = cursor.take_due() {
+                            queue.publish(position, Some(source_id.clone()));
+                        }
+                    },
+                    
+                    // Ping a quiet client, and give up on one that stopped answering
+                    _ = rocket::tokio::time::sleep_until(heartbeat.deadline()) => {
+                        match heartbeat.beat() {
+                            Beat::Ping => {
+                                if stream.send(Message::Ping(Vec::new())).await.is_err() {
                                     break;
                                 }
+                            },
+                            Beat::TimedOut => {
+                                println!("WebSocket {} missed its pong, closing", ws_client_id);
+                                let close = CloseFrame { code: CloseCode::Away, reason: "Heartbeat timeout".into() };
+                                let _ = rocket::tokio::time::timeout(Duration::from_secs(1), stream.close(Some(close))).await;
+                                break;
                             }
-                        } else {
-                            // Error receiving broadcast message
-                            break;
                         }
                     },
                     
                     // Handle incoming messages from WebSocket
                     msg = stream.next() => {
+                        if let Some(Ok(_)) = msg {
+                            heartbeat.seen();
+                        }
                         match msg {
+                            // htmx clients only listen; anything they send is ignored
+                            Some(Ok(Message::Text(_))) if format == SocketFormat::Htmx => {},
                             Some(Ok(Message::Text(text))) => {
                                 println!("Received message from client: {}", text);
-                                // Try to parse as JSON
-                                if let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) {
-                                    // If this is a client ID message, store it
-                                    if let Some(client_id) = value.get("client_id") {
-                                        if let Some(id_str) = client_id.as_str() {
-                                            println!("WebSocket client {} identified as {}", ws_client_id, id_str);
-                                        }
-                                    }
-                                    
-                                    // If this is a real-time edit update
-                                    if let Some(event) = value.get("event") {
-                                        if event.as_str() == Some("edit_update") {
-                                            if let (Some(todo_id), Some(content)) = (
-                                                value.get("todo_id").and_then(|v| v.as_i64()),
-                                                value.get("content").and_then(|v| v.as_str())
-                                            ) {
-                                                // Get client ID from message if available
-                                                let source_id = value.get("client_id")
-                                                    .and_then(|v| v.as_str())
-                                                    .map(|s| s.to_string())
-                                                    .unwrap_or_else(|| ws_client_id.clone());
-                                                
-                                                // Broadcast the edit to all clients
-                                                let _ = queue.send(TodoUpdate {
-                                                    event: "edit_update".to_string(),
-                                                    todo_id: Some(todo_id),
-                                                    source_id: Some(source_id),
-                                                    content: Some(content.to_string()),
-                                                    connected_users: None,
-                                                });
-                                            }
-                                        }
-                                        
-                                        // If this is a save edit
-                                        if event.as_str() == Some("save_edit") {
-                                            if let (Some(todo_id), Some(content)) = (
-                                                value.get("todo_id").and_then(|v| v.as_i64()),
-                                                value.get("content").and_then(|v| v.as_str())
-                                            ) {
-                                                // Get client ID from message if available
-                                                let source_id = value.get("client_id")
-                                                    .and_then(|v| v.as_str())
-                                                    .map(|s| s.to_string())
-                                                    .unwrap_or_else(|| ws_client_id.clone());
-                                                
-                                                // Actually save the edit to the database
-                                                if (update_todo(todo_id, &content.to_string()).await).is_ok() {
-                                                    println!("Saved edit for todo {}: {}", todo_id, content);
-                                                    
-                                                    // Send confirmation back to client
-                                                    let confirm_msg = json!({
-                                                        "event": "edit_saved",
-                                                        "todo_id": todo_id,
-                                                        "success": true
-                                                    });
-                                                    
-                                                    if let Ok(confirm_str) = serde_json::to_string(&confirm_msg) {
-                                                        let _ = stream.send(Message::Text(confirm_str)).await;
-                                                    }
-                                                    
-                                                    // Broadcast final update to all clients
-                                                    let _ = queue.send(TodoUpdate {
-                                                        event: "update".to_string(),
-                                                        todo_id: Some(todo_id),
-                                                        source_id: Some(source_id),
-                                                        content: Some(content.to_string()),
-                                                        connected_users: None,
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
+                                let result = match ClientMessage::parse(&text) {
+                                    Ok(client_msg) => handle_client_message(client_msg, &mut stream, &socket, &mut last_sent_seq, &mut cursor).await,
+                                    Err(error) => send_message(&mut stream, &error).await,
+                                };
+                                if result.is_err() {
+                                    break;
+                                }
+                            },
+                            Some(Ok(Message::Binary(_))) => {
+                                let error = ServerMessage::Error {
+                                    code: ErrorCode::Unsupported,
+                                    message: "Binary frames are not supported".to_string(),
+                                };
+                                if send_message(&mut stream, &error).await.is_err() {
+                                    break;
                                 }
                             },
                             Some(Ok(Message::Close(_))) => {
-                                // Remove this client from active sessions and get updated count
-                                let connected_users = sessions.remove_client(&ws_client_id);
-                                println!("WebSocket connection closed: {}. Total connected users: {}", ws_client_id, connected_users);
-                                
-                                // Debug print sessions after disconnect
-                                sessions.debug_print();
-                                
-                                // Broadcast user count update
-                                let _ = queue.send(TodoUpdate {
-                                    event: "user_count".to_string(),
-                                    todo_id: None,
-                                    source_id: None,
-                                    content: None,
-                                    connected_users: Some(connected_users),
-                                });
-                                
+                                println!("WebSocket connection closed: {}", ws_client_id);
+                                break;
+                            },
+                            Some(Err(e)) => {
+                                println!("WebSocket connection error: {}: {}", ws_client_id, e);
                                 break;
                             },
                             None => {
-                                // Remove this client from active sessions and get updated count
-                                let connected_users = sessions.remove_client(&ws_client_id);
-                                println!("WebSocket connection lost: {}. Total connected users: {}", ws_client_id, connected_users);
-                                
-                                // Debug print sessions after disconnect
-                                sessions.debug_print();
-                                
-                                // Broadcast user count update
-                                let _ = queue.send(TodoUpdate {
-                                    event: "user_count".to_string(),
-                                    todo_id: None,
-                                    source_id: None,
-                                    content: None,
-                                    connected_users: Some(connected_users),
-                                });
-                                
+                                println!("WebSocket connection lost: {}", ws_client_id);
                                 break;
                             }
                             _ => {}
@@ -368,111 +513,668 @@ fn todo_websocket<'r>(ws: WebSocket, queue: &'r State<Sender<TodoUpdate>>, sessi
             
             Ok(())
         })
-    })
+    }))
 }
 
-#[derive(FromForm)]
+// Replace an htmx client's whole list with freshly rendered cards
+async fn send_cards(stream: &mut DuplexStream, live: &Live<'_>, pool: &SqlitePool, list_id: i64) -> Result<(), rocket_ws::result::Error> {
+    let Some(cards) = get_todos(pool, list_id, &TodoQuery::default()).await.ok().and_then(|todos| live.cards(&todos)) else {
+        return Ok(());
+    };
+    stream.send(Message::Text(format!(r#"<div id="todo-cards" hx-swap-oob="innerHTML">{}</div>"#, cards))).await
+}
+
+// Replay missed events to a reconnecting client, or send a full resync when
+// they're no longer in history. Returns the last seq delivered.
+async fn resume(stream: &mut impl Replies, queue: &Broadcaster, pool: &SqlitePool, list_id: i64, epoch: u32, last_seq: u64) -> Result<u64, rocket_ws::result::Error> {
+    if let Some(missed) = queue.replay_since(epoch, last_seq) {
+        println!("Replaying {} events after seq {}", missed.len(), last_seq);
+        let mut seq = last_seq;
+        for update in missed {
+            stream.reply(&update).await?;
+            seq = update.seq;
+        }
+        stream.reply(&ServerMessage::Resumed { seq }).await?;
+        return Ok(seq);
+    }
+    
+    println!("Cannot replay from seq {} (epoch {}), sending resync", last_seq, epoch);
+    let seq = queue.current_seq();
+    match get_todos(pool, list_id, &TodoQuery::default()).await {
+        Ok(todos) => {
+            stream.reply(&ServerMessage::Resync { todos, seq, epoch: queue.epoch() }).await?;
+            Ok(seq)
+        }
+        Err(e) => {
+            stream.reply(&ServerMessage::Error {
+                code: ErrorCode::Failed,
+                message: format!("Could not load todos: {}", e),
+            }).await?;
+            Ok(last_seq)
+        }
+    }
+}
+
+// Everything the message handlers need to know about one socket
+struct SocketContext<'a> {
+    connection_id: &'a str,
+    source_id: &'a str,
+    list_id: i64,
+    queue: &'a Broadcaster,
+    live: &'a Live<'a>,
+    pool: &'a SqlitePool,
+    titles: &'a TitleDocs,
+    sessions: &'a ClientSessions,
+}
+
+// Apply a message from a client, replying on its socket where needed
+async fn handle_client_message(msg: ClientMessage, stream: &mut impl Replies, socket: &SocketContext<'_>, last_sent_seq: &mut u64, cursor: &mut CursorThrottle) -> Result<(), rocket_ws::result::Error> {
+    let SocketContext { connection_id, source_id, list_id, queue, live, pool, titles, sessions } = *socket;
+    match msg {
+        ClientMessage::EditOpen { todo_id, lock } => {
+            if todo_in_list(stream, pool, todo_id, list_id).await?.is_none() {
+                return Ok(());
+            }
+            
+            // Registered as an editor first: an event stream ending meanwhile either
+            // refuses edit mode below or drops the registration as it leaves
+            let opened = titles.open(todo_id, connection_id);
+            let started = sessions.start_editing(list_id, connection_id, todo_id, lock);
+            if opened && started.is_err() {
+                titles.close(todo_id, connection_id);
+            }
+            
+            // Someone else holds the lock, so stay out of edit mode
+            let (presence, previous) = match started {
+                Ok(started) => started,
+                Err(EditRefused::Locked(holder, remaining)) => {
+                    return stream.reply(&ServerMessage::Locked {
+                        todo_id,
+                        user: holder,
+                        expires_in_ms: remaining.as_millis() as u64,
+                    }).await;
+                }
+                Err(EditRefused::NotConnected) => {
+                    return send_error(stream, ErrorCode::Invalid, "Not connected to this list".to_string()).await;
+                }
+            };
+            if let Some(previous) = previous {
+                titles.close(previous, connection_id);
+                queue.publish(ServerMessage::EditingStopped { todo_id: previous, connection_id: connection_id.to_string() }, Some(source_id.to_string()));
+            }
+            queue.publish(ServerMessage::EditingStarted { todo_id, user: Box::new(presence) }, Some(source_id.to_string()));
+            // Gone since, and its goodbye may have gone out before the line above
+            if sessions.presence(list_id, connection_id).is_none() {
+                queue.publish(ServerMessage::EditingStopped { todo_id, connection_id: connection_id.to_string() }, Some(source_id.to_string()));
+                return Ok(());
+            }
+            
+            match titles.state(pool, todo_id).await {
+                Ok(state) => stream.reply(&ServerMessage::EditState { todo_id, update: BASE64.encode(state) }).await,
+                Err(e) => send_error(stream, ErrorCode::Failed, format!("Could not open todo {}: {}", todo_id, e)).await,
+            }
+        }
+        ClientMessage::EditUpdate { todo_id, update } => {
+            if todo_in_list(stream, pool, todo_id, list_id).await?.is_none() {
+                return Ok(());
+            }
+            let Ok(bytes) = BASE64.decode(&update) else {
+                return send_error(stream, ErrorCode::Invalid, "Title updates must be base64".to_string()).await;
+            };
+            sessions.refresh_lock(list_id, connection_id, todo_id);
+            
+            // Merge into the server's copy and save, then relay the same ops to everyone
+            match titles.apply(pool, todo_id, &bytes).await {
+                Ok(content) => {
+                    queue.publish(ServerMessage::EditUpdate { todo_id, update, content }, Some(source_id.to_string()));
+                    Ok(())
+                }
+                Err(e @ TitleError::InvalidUpdate(_)) => send_error(stream, ErrorCode::Invalid, e.to_string()).await,
+                Err(e) => send_error(stream, ErrorCode::Failed, format!("Could not save todo {}: {}", todo_id, e)).await,
+            }
+        }
+        ClientMessage::SaveEdit { todo_id, content } => {
+            // Every edit is already merged and saved, so version the typing and announce the result
+            let Some(mut todo) = todo_in_list(stream, pool, todo_id, list_id).await? else {
+                return Ok(());
+            };
+            let committed = match titles.commit(pool, todo_id).await {
+                Ok(true) => get_todo(pool, todo_id).await.map(Some),
+                Ok(false) => Ok(None),
+                Err(e) => Err(e),
+            };
+            match committed {
+                Ok(Some(saved)) => todo = saved,
+                Ok(None) => {}
+                Err(e) => return send_error(stream, ErrorCode::Failed, format!("Could not save todo {}: {}", todo_id, e)).await,
+            }
+            cursor.clear();
+            titles.close(todo_id, connection_id);
+            if sessions.stop_editing(list_id, connection_id, todo_id) {
+                queue.publish(ServerMessage::EditingStopped { todo_id, connection_id: connection_id.to_string() }, Some(source_id.to_string()));
+            }
+            
+            // The client finished on a different title than the merge, so show it what won
+            if content.is_some_and(|content| content != todo.title) {
+                return stream.reply(&ServerMessage::Conflict {
+                    todo_id,
+                    content: todo.title,
+                    version: todo.version,
+                }).await;
+            }
+            println!("Saved edit for todo {}: {}", todo_id, todo.title);
+            
+            // Broadcast final update to all clients
+            let html = live.card(&todo);
+            queue.publish(ServerMessage::Update { todo_id, content: Some(todo.title), html }, Some(source_id.to_string()));
+            
+            // Send confirmation back to client
+            stream.reply(&ServerMessage::EditSaved { todo_id, success: true }).await
+        }
+        ClientMessage::Cursor { todo_id, start, end } => {
+            // Only relayed while the sender has the todo open; stragglers after a save are dropped
+            let Some(user) = sessions.presence(list_id, connection_id).filter(|p| p.editing == Some(todo_id)) else {
+                return Ok(());
+            };
+            let position = ServerMessage::Cursor { todo_id, start, end, user: Box::new(user) };
+            if let Some(position) = cursor.offer(position) {
+                queue.publish(position, Some(source_id.to_string()));
+            }
+            Ok(())
+        }
+        ClientMessage::Move { todo_id, before, after } => {
+            if todo_in_list(stream, pool, todo_id, list_id).await?.is_none() {
+                return Ok(());
+            }
+            match move_todo(pool, todo_id, before, after).await {
+                Ok(position) => {
+                    queue.publish(ServerMessage::Move { todo_id, position, before, after }, Some(source_id.to_string()));
+                    Ok(())
+                }
+                Err(e) => send_error(stream, ErrorCode::Failed, format!("Could not move todo {}: {}", todo_id, e)).await,
+            }
+        }
+        ClientMessage::Resume { last_seq, epoch } => {
+            let seq = resume(stream, queue, pool, list_id, epoch, last_seq).await?;
+            *last_sent_seq = (*last_sent_seq).max(seq);
+            Ok(())
+        }
+    }
+}
+
+// Only todos in this socket's list may be edited through it; replies with an error otherwise
+async fn todo_in_list(stream: &mut impl Replies, pool: &SqlitePool, todo_id: i64, list_id: i64) -> Result<Option<Todo>, rocket_ws::result::Error> {
+    match get_todo(pool, todo_id).await {
+        Ok(todo) if todo.list_id == list_id => Ok(Some(todo)),
+        _ => {
+            send_error(stream, ErrorCode::NotFound, format!("Todo {} is not in this list", todo_id)).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn send_error(stream: &mut impl Replies, code: ErrorCode, message: String) -> Result<(), rocket_ws::result::Error> {
+    stream.reply(&ServerMessage::Error { code, message }).await
+}
+
+#[derive(FromForm, utoipa::ToSchema)]
 struct TodoForm {
     title: String,
+    // Adds the new todo as a subtask of this one, in its list; ignored by edits
+    parent_id: Option<i64>,
+    #[schema(value_type = Option<String>)]
+    due_at: When,
+    #[schema(value_type = Option<String>)]
+    remind_at: When,
+    priority: Option<Priority>,
+    // Separated by commas or spaces
+    tags: Option<String>,
+    // An RFC 5545 recurrence rule like FREQ=WEEKLY;BYDAY=MO; blank stops it repeating
+    #[schema(value_type = Option<String>)]
+    recurrence: Repeat,
+}
+
+// Changes to a todo from the page; a field left out stays as it is
+#[derive(FromForm, utoipa::ToSchema)]
+struct EditForm {
+    title: Option<String>,
+    #[schema(value_type = Option<String>)]
+    due_at: When,
+    #[schema(value_type = Option<String>)]
+    remind_at: When,
+    priority: Option<Priority>,
+    // Separated by commas or spaces
+    tags: Option<String>,
+    // An RFC 5545 recurrence rule like FREQ=WEEKLY;BYDAY=MO; blank stops it repeating
+    #[schema(value_type = Option<String>)]
+    recurrence: Repeat,
+}
+
+impl EditForm {
+    fn edit(&self) -> TodoEdit {
+        TodoEdit {
+            title: self.title.clone(),
+            completed: None,
+            details: Details {
+                due_at: self.due_at,
+                remind_at: self.remind_at,
+                priority: self.priority,
+                tags: self.tags.as_deref().map(parse_tags),
+                recurrence: self.recurrence.clone(),
+            },
+        }
+    }
+}
+
+// Changes to a todo besides its title and completed state, however they arrived.
+// None and When::Unchanged leave a field alone.
+#[derive(Default)]
+pub struct Details {
+    pub due_at: When,
+    pub remind_at: When,
+    pub priority: Option<Priority>,
+    pub tags: Option<Vec<String>>,
+    pub recurrence: Repeat,
+}
+
+impl Details {
+    // The changes to write: whatever differs from the todo as it is
+    pub fn changes(self, todo: &Todo) -> TodoChanges {
+        let due_at = self.due_at.apply(todo.due_at);
+        let remind_at = self.remind_at.apply(todo.remind_at);
+        let recurrence = self.recurrence.apply(todo.recurrence.clone());
+        TodoChanges {
+            due_at: Some(due_at).filter(|due_at| *due_at != todo.due_at),
+            remind_at: Some(remind_at).filter(|remind_at| *remind_at != todo.remind_at),
+            priority: self.priority.filter(|priority| *priority != todo.priority),
+            tags: self.tags.filter(|tags| *tags != todo.tags),
+            recurrence: Some(recurrence).filter(|recurrence| *recurrence != todo.recurrence),
+            ..Default::default()
+        }
+    }
+}
+
+// Everything one request changes about a todo
+pub struct TodoEdit {
+    pub title: Option<String>,
+    pub completed: Option<bool>,
+    pub details: Details,
 }
 
-#[post("/todos", data = "<form>")]
-async fn post_todos(cookies: &CookieJar<'_>, sessions: &State<ClientSessions>, form: Form<TodoForm>, queue: &State<Sender<TodoUpdate>>) -> String {
-    let client_id = get_client_id(cookies, sessions);
-    let id = add_todo(&form.title).await.unwrap_or(-1);
+// Write whatever differs from the todo in one transaction under a single new version,
+// then announce it. A new title goes through the title document so anyone mid-edit
+// merges with it. An edit that changes nothing still fails on a stale expected version.
+pub async fn save_edit(live: &Live<'_>, pool: &SqlitePool, titles: &TitleDocs, todo: Todo, edit: TodoEdit, expected_version: Option<i64>, source_id: String) -> Result<Todo, DbError> {
+    let id = todo.id;
+    let title = edit.title.filter(|title| *title != todo.title);
+    let completed = edit.completed.filter(|completed| *completed != todo.completed);
+    let mut changes = edit.details.changes(&todo);
+    let details_changed = !changes.is_empty();
+    changes.completed = completed;
+    
+    let (update, completion) = match &title {
+        Some(title) => {
+            let (update, completion) = titles.replace(pool, id, title, changes, expected_version).await?;
+            (Some(update), completion)
+        }
+        None if !changes.is_empty() => (None, edit_todo(pool, id, &changes, expected_version).await?),
+        None => {
+            return match expected_version.filter(|expected| *expected != todo.version) {
+                Some(expected) => Err(DbError::VersionMismatch { current: todo.version, expected }),
+                None => Ok(todo),
+            };
+        }
+    };
     
-    // Return error if adding failed
-    if id == -1 {
-        return "Error adding todo".to_string();
+    // Announced only once all of it is saved
+    let todo = get_todo(pool, id).await?;
+    let queue = live.room(todo.list_id);
+    if let Some(update) = update {
+        queue.publish(ServerMessage::EditUpdate { todo_id: id, update: BASE64.encode(update), content: todo.title.clone() }, Some(source_id.clone()));
     }
+    if completed.is_some() {
+        publish_toggle(live, pool, &todo, &completion, source_id.clone()).await;
+    }
+    if title.is_some() || details_changed {
+        let content = title.map(|_| todo.title.clone());
+        queue.publish(ServerMessage::Update { todo_id: id, content, html: live.card(&todo) }, Some(source_id));
+    }
+    Ok(todo)
+}
+
+#[utoipa::path(
+    tag = "htmx",
+    params(("list" = Option<String>, Query, description = "List slug; the default list when left out")),
+    request_body(content = TodoForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Id of the new todo; broadcast as `add`", content_type = "text/plain", body = String),
+        (status = 404, description = "No such list or parent todo"),
+        (status = 422, description = "The parent is already as deep as subtasks go, or a date or recurrence rule that can't be read"),
+    ),
+    security(("session" = []))
+)]
+#[post("/todos?<list>", data = "<form>")]
+async fn post_todos(user: User, pool: &State<SqlitePool>, list: Option<&str>, form: Form<TodoForm>, live: Live<'_>) -> Result<String, DbError> {
+    let list_id = match form.parent_id {
+        Some(parent_id) => get_todo(pool, parent_id).await?.list_id,
+        None => resolve_list(pool, list).await?.id,
+    };
+    let tags = form.tags.as_deref().map(parse_tags).unwrap_or_default();
+    let recurrence = form.recurrence.clone().apply(None);
+    let draft = TodoDraft {
+        title: &form.title,
+        parent_id: form.parent_id,
+        due_at: form.due_at.apply(None),
+        remind_at: form.remind_at.apply(None),
+        priority: form.priority.unwrap_or_default(),
+        tags: &tags,
+        recurrence: recurrence.as_deref(),
+    };
+    let id = add_todo(pool, list_id, &draft).await?;
     
     println!("✅ Created new todo with id: {}", id);
     
     // Broadcast the new todo to all clients, but don't include user count
-    let _ = queue.send(TodoUpdate {
-        event: "add".to_string(),
-        todo_id: Some(id),
-        source_id: Some(client_id),  // Include source_id to identify source
-        content: None,
-        connected_users: None,  // Don't send connected users here
-    });
+    let todo = get_todo(pool, id).await?;
+    publish_add(&live, pool, &todo, user.source_id()).await;
     
     // Just return the ID as a simple string
-    id.to_string()
+    Ok(id.to_string())
+}
+
+// Announce a new todo. A subtask also shows in its top-level todo's progress.
+async fn publish_add(live: &Live<'_>, pool: &SqlitePool, todo: &Todo, source_id: String) {
+    live.room(todo.list_id).publish(ServerMessage::Add { todo_id: todo.id, parent_id: todo.parent_id, html: live.card(todo) }, Some(source_id.clone()));
+    if let Some(parent_id) = todo.parent_id {
+        publish_root_card(live, pool, parent_id, source_id).await;
+    }
+}
+
+// Announce a completed state change along with the todos it cascaded to, and the
+// next occurrence of a recurring todo as an add
+async fn publish_toggle(live: &Live<'_>, pool: &SqlitePool, todo: &Todo, completion: &Completion, source_id: String) {
+    let queue = live.room(todo.list_id);
+    queue.publish(ServerMessage::Toggle { todo_id: todo.id, completed: todo.completed, html: live.card(todo) }, Some(source_id.clone()));
+    match get_todos_by_id(pool, &completion.cascaded).await {
+        Ok(others) => {
+            for other in others {
+                queue.publish(ServerMessage::Toggle { todo_id: other.id, completed: other.completed, html: live.card(&other) }, Some(source_id.clone()));
+            }
+        }
+        Err(e) => println!("Failed to load the todos toggled along with todo {}: {}", todo.id, e),
+    }
+    if todo.parent_id.is_some() || !todo.subtasks.is_empty() {
+        publish_root_card(live, pool, todo.id, source_id.clone()).await;
+    }
+    if let Some(next) = completion.next {
+        match get_todo(pool, next).await {
+            Ok(next) => publish_add(live, pool, &next, source_id).await,
+            Err(e) => println!("Failed to load the next occurrence of todo {}: {}", todo.id, e),
+        }
+    }
+}
+
+// Announce a delete and the subtasks that went with it
+async fn publish_delete(live: &Live<'_>, pool: &SqlitePool, titles: &TitleDocs, todo: &Todo, subtasks: &[i64], source_id: String) {
+    let queue = live.room(todo.list_id);
+    for id in subtasks.iter().chain([&todo.id]) {
+        titles.forget(*id);
+        queue.publish(ServerMessage::Delete { todo_id: *id }, Some(source_id.clone()));
+    }
+    if let Some(parent_id) = todo.parent_id {
+        publish_root_card(live, pool, parent_id, source_id).await;
+    }
 }
 
+// Subtasks are drawn inside the card of their top-level todo, so after a change that
+// shows in its progress, send that whole card again
+async fn publish_root_card(live: &Live<'_>, pool: &SqlitePool, todo_id: i64, source_id: String) {
+    let root = match root_todo_id(pool, todo_id).await {
+        Ok(root_id) => get_todo(pool, root_id).await,
+        Err(e) => Err(e),
+    };
+    match root {
+        Ok(root) => {
+            live.room(root.list_id).publish(ServerMessage::Update { todo_id: root.id, content: None, html: live.card(&root) }, Some(source_id));
+        }
+        Err(e) => println!("Failed to load the top-level todo of {}: {}", todo_id, e),
+    }
+}
+
+#[utoipa::path(
+    tag = "htmx",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the edit is based on"),
+    ),
+    request_body(content = EditForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The updated card; a new title is broadcast as `edit_update`, any change as `update`", content_type = "text/html", body = String,
+            headers(("ETag" = String, description = "Quoted new version"))),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo is no longer at the If-Match version"),
+    ),
+    security(("session" = []))
+)]
 #[post("/todo-edit/<id>", data = "<form>")]
-async fn post_todo_edit(cookies: &CookieJar<'_>, sessions: &State<ClientSessions>, id: i64, form: Form<TodoForm>, queue: &State<Sender<TodoUpdate>>) -> Result<Template, Status> {
-    let client_id = get_client_id(cookies, sessions);
-    update_todo(id, &form.title).await?;
-    let todo = get_todo(id).await?;
-    
-    // Broadcast update to all clients, but don't include user count
-    let _ = queue.send(TodoUpdate {
-        event: "update".to_string(),
-        todo_id: Some(id),
-        source_id: Some(client_id),
-        content: None,
-        connected_users: None,  // Don't send connected users here
-    });
+async fn post_todo_edit(user: User, pool: &State<SqlitePool>, titles: &State<TitleDocs>, id: i64, if_match: IfMatch, form: Form<EditForm>, live: Live<'_>) -> Result<Versioned<Template>, DbError> {
+    let todo = get_todo(pool, id).await?;
+    let todo = save_edit(&live, pool, titles, todo, form.edit(), if_match.0, user.source_id()).await?;
     
-    Ok(Template::render(
+    let version = todo.version;
+    Ok(Versioned::new(Template::render(
         "todo-read",
         context! {
-            todo
+            todo,
+            deepest_parent: DEEPEST_PARENT
         },
-    ))
+    ), version))
 }
 
+#[utoipa::path(
+    tag = "htmx",
+    params(("id" = i64, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "The card in edit mode", content_type = "text/html", body = String,
+            headers(("ETag" = String, description = "Quoted version"))),
+        (status = 404, description = "No such todo"),
+    ),
+    security(("session" = []))
+)]
 #[get("/todo-edit/<id>")]
-async fn get_todo_edit(id: i64) -> Result<Template, Status> {
-    let todo = get_todo(id).await?;
-    Ok(Template::render(
+async fn get_todo_edit(_user: User, pool: &State<SqlitePool>, id: i64) -> Result<Versioned<Template>, DbError> {
+    let todo = get_todo(pool, id).await?;
+    let version = todo.version;
+    Ok(Versioned::new(Template::render(
         "todo-read",
         context! {
             todo,
-            edit_mode: true
+            edit_mode: true,
+            deepest_parent: DEEPEST_PARENT
         },
-    ))
+    ), version))
 }
 
+#[utoipa::path(
+    tag = "htmx",
+    params(("id" = i64, Path, description = "Todo id")),
+    responses(
+        (status = 200, description = "The card", content_type = "text/html", body = String,
+            headers(("ETag" = String, description = "Quoted version"))),
+        (status = 404, description = "No such todo"),
+    ),
+    security(("session" = []))
+)]
 #[get("/todo-read/<id>")]
-async fn get_todo_read(id: i64) -> Result<Template, Status> {
-    let todo = get_todo(id).await?;
+async fn get_todo_read(_user: User, pool: &State<SqlitePool>, id: i64) -> Result<Versioned<Template>, DbError> {
+    let todo = get_todo(pool, id).await?;
     println!("GET todo_read for id {}", id);
-    Ok(Template::render(
+    let version = todo.version;
+    Ok(Versioned::new(Template::render(
+        "todo-read",
+        context! {
+            todo,
+            deepest_parent: DEEPEST_PARENT
+        },
+    ), version))
+}
+
+// Flip the completed state of a todo
+#[utoipa::path(
+    tag = "htmx",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the toggle is based on"),
+    ),
+    responses(
+        (status = 200, description = "The updated card; broadcast as `toggle`, also for subtasks completed and parents reopened with it, \
+            and the next occurrence of a recurring todo as `add`", content_type = "text/html", body = String,
+            headers(("ETag" = String, description = "Quoted new version"))),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo is no longer at the If-Match version"),
+    ),
+    security(("session" = []))
+)]
+#[post("/todo-toggle/<id>")]
+async fn post_todo_toggle(user: User, pool: &State<SqlitePool>, id: i64, if_match: IfMatch, live: Live<'_>) -> Result<Versioned<Template>, DbError> {
+    let current = get_todo(pool, id).await?;
+    // Without If-Match, flip whatever we just read
+    let expected_version = if_match.0.or(Some(current.version));
+    let completion = set_todo_completed(pool, id, !current.completed, expected_version).await?;
+    let todo = get_todo(pool, id).await?;
+    
+    // Broadcast the new completed states so every client flips the checkboxes
+    publish_toggle(&live, pool, &todo, &completion, user.source_id()).await;
+    
+    let version = todo.version;
+    Ok(Versioned::new(Template::render(
         "todo-read",
         context! {
-            todo
+            todo,
+            deepest_parent: DEEPEST_PARENT
         },
-    ))
+    ), version))
+}
+
+// Change the due date, reminder, priority, tags or recurrence of a todo
+#[utoipa::path(
+    tag = "htmx",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the change is based on"),
+    ),
+    request_body(content = EditForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The updated card; broadcast as `update`", content_type = "text/html", body = String,
+            headers(("ETag" = String, description = "Quoted new version"))),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo is no longer at the If-Match version"),
+        (status = 422, description = "A date or recurrence rule that can't be read"),
+    ),
+    security(("session" = []))
+)]
+#[post("/todo-details/<id>", data = "<form>")]
+async fn post_todo_details(user: User, pool: &State<SqlitePool>, titles: &State<TitleDocs>, id: i64, if_match: IfMatch, form: Form<EditForm>, live: Live<'_>) -> Result<Versioned<Template>, DbError> {
+    let todo = get_todo(pool, id).await?;
+    let todo = save_edit(&live, pool, titles, todo, form.edit(), if_match.0, user.source_id()).await?;
+    
+    let version = todo.version;
+    Ok(Versioned::new(Template::render(
+        "todo-read",
+        context! {
+            todo,
+            deepest_parent: DEEPEST_PARENT
+        },
+    ), version))
+}
+
+// Where a dragged todo was dropped: between the todos now right above and below it
+#[derive(FromForm, utoipa::ToSchema)]
+struct MoveForm {
+    // Left out at the top of the list
+    before: Option<i64>,
+    // Left out at the bottom of the list
+    after: Option<i64>,
+}
+
+// Reorder a todo by hand; only its own position changes
+#[utoipa::path(
+    tag = "htmx",
+    params(("id" = i64, Path, description = "Todo id")),
+    request_body(content = MoveForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Moved; broadcast as `move`", content_type = "text/plain", body = String),
+        (status = 404, description = "No such todo, or a neighbor that isn't in its list"),
+        (status = 409, description = "The neighbors are no longer in that order"),
+    ),
+    security(("session" = []))
+)]
+#[post("/todo-move/<id>", data = "<form>")]
+async fn post_todo_move(user: User, pool: &State<SqlitePool>, id: i64, form: Form<MoveForm>, rooms: &State<Rooms>) -> Result<String, DbError> {
+    let todo = get_todo(pool, id).await?;
+    let position = move_todo(pool, id, form.before, form.after).await?;
+    
+    rooms.get(todo.list_id).publish(ServerMessage::Move { todo_id: id, position: position.clone(), before: form.before, after: form.after }, Some(user.source_id()));
+    
+    // The new position, for clients that keep their own order
+    Ok(position)
 }
 
 // Add a new endpoint to delete a specific todo
+#[utoipa::path(
+    tag = "htmx",
+    params(
+        ("id" = i64, Path, description = "Todo id"),
+        ("If-Match" = Option<String>, Header, description = "Version the delete is based on"),
+    ),
+    responses(
+        (status = 200, description = "Deleted along with its subtasks; broadcast as a `delete` for each"),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo is no longer at the If-Match version"),
+    ),
+    security(("session" = []))
+)]
 #[post("/todo-delete/<id>")]
-async fn delete_todo_endpoint(cookies: &CookieJar<'_>, sessions: &State<ClientSessions>, id: i64, queue: &State<Sender<TodoUpdate>>) -> Status {
-    let client_id = get_client_id(cookies, sessions);
+async fn delete_todo_endpoint(user: User, pool: &State<SqlitePool>, titles: &State<TitleDocs>, id: i64, if_match: IfMatch, live: Live<'_>) -> Result<Status, DbError> {
     
-    // Delete the todo
-    if (delete_todo(id).await).is_err() {
-        return Status::InternalServerError;
-    }
+    // Look up the list first so the right room hears about it
+    let todo = get_todo(pool, id).await?;
     
-    // Broadcast delete event to all clients, but don't include user count
-    let _ = queue.send(TodoUpdate {
-        event: "delete".to_string(),
-        todo_id: Some(id),
-        source_id: Some(client_id),
-        content: None,
-        connected_users: None,  // Don't send connected users here
-    });
+    // Delete the todo and its subtasks
+    let subtasks = delete_todo(pool, id, if_match.0).await?;
     
-    Status::Ok
+    // Broadcast delete events to all clients, but don't include user count
+    publish_delete(&live, pool, titles, &todo, &subtasks, user.source_id()).await;
+    
+    Ok(Status::Ok)
 }
 
 impl From<DbError> for Status {
-    fn from(_: DbError) -> Self {
-        Status::InternalServerError
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::NotFound(_) => Status::NotFound,
+            DbError::Conflict(_) | DbError::OutOfOrder => Status::Conflict,
+            DbError::Constraint(_) | DbError::TooDeep { .. } => Status::UnprocessableEntity,
+            DbError::VersionMismatch { .. } => Status::PreconditionFailed,
+            DbError::Io(_) | DbError::Other(_) => Status::InternalServerError,
+        }
+    }
+}
+
+// Respond with the mapped status and a rendered error fragment
+impl<'r> Responder<'r, 'static> for DbError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        println!("Database error: {:?}", self);
+        let message = self.to_string();
+        let status = Status::from(self);
+        let template = Template::render(
+            "error",
+            context! {
+                status: status.code,
+                message
+            },
+        );
+        (status, template).respond_to(req)
     }
 }
 
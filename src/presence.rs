@@ -0,0 +1,402 @@
+use crate::broadcast::Broadcaster;
+use crate::crdt::TitleDocs;
+use crate::db::User;
+use crate::heartbeat::HeartbeatConfig;
+use crate::protocol::ServerMessage;
+use serde::Serialize;
+use utoipa::ToSchema;
+use rocket::tokio::sync::{Mutex as AsyncMutex, Notify};
+use rocket::tokio::time::Instant as TokioInstant;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Who is behind one WebSocket connection and what they're doing
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Presence {
+    pub connection_id: String,
+    pub source_id: String,
+    pub display_name: String,
+    pub color: String,
+    // Todo currently open in edit mode
+    pub editing: Option<i64>,
+}
+
+impl Presence {
+    pub fn new(connection_id: String, user: &User) -> Self {
+        Presence {
+            connection_id,
+            source_id: user.source_id(),
+            display_name: user.display_name(),
+            // Spread user ids around the colour wheel so each person keeps one colour
+            color: format!("hsl({}, 65%, 45%)", (user.id * 137).rem_euclid(360)),
+            editing: None,
+        }
+    }
+}
+
+// Advisory lock on a todo; other people are refused edit mode until it expires
+struct EditLock {
+    connection_id: String,
+    source_id: String,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct ListSessions {
+    connections: HashMap<String, Presence>,
+    // Connections per signed-in identity, so several tabs count as one person
+    people: HashMap<String, HashSet<String>>,
+    locks: HashMap<i64, EditLock>,
+}
+
+impl ListSessions {
+    fn headcount(&self) -> Headcount {
+        Headcount {
+            people: self.people.len(),
+            connections: self.connections.len(),
+        }
+    }
+}
+
+// Why a connection can't go into edit mode
+#[derive(Debug)]
+pub enum EditRefused {
+    // Someone else holds the todo's lock, for this much longer
+    Locked(Box<Presence>, Duration),
+    // The connection already left the list, e.g. a POST racing its event stream's end
+    NotConnected,
+}
+
+// Distinct people on a list and the sockets they have open
+#[derive(Debug, Clone, Copy)]
+pub struct Headcount {
+    pub people: usize,
+    pub connections: usize,
+}
+
+// What an event stream keeps between the POSTs sent for it. A WebSocket holds the
+// same in its own loop.
+pub struct StreamState {
+    pub cursor: AsyncMutex<CursorThrottle>,
+    // Newest seq the client has, from the stream or a POST's replies
+    pub last_sent_seq: AtomicU64,
+    // Wakes the stream when a POST leaves a cursor held back for later
+    pub cursor_held: Notify,
+}
+
+// Track client sessions and edit locks per list
+pub struct ClientSessions {
+    lists: Arc<Mutex<HashMap<i64, ListSessions>>>,
+    streams: Mutex<HashMap<String, Arc<StreamState>>>,
+    lock_timeout: Duration,
+    heartbeat: HeartbeatConfig,
+}
+
+impl ClientSessions {
+    pub fn new(lock_timeout: Duration, heartbeat: HeartbeatConfig) -> Self {
+        ClientSessions {
+            lists: Arc::default(),
+            streams: Mutex::default(),
+            lock_timeout,
+            heartbeat,
+        }
+    }
+
+    // Keepalive settings for each connection
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        self.heartbeat
+    }
+
+    // Add a client to a list and return the new count for that list
+    pub fn add_client(&self, list_id: i64, presence: Presence) -> Headcount {
+        let mut lists = self.lists.lock().unwrap();
+        let sessions = lists.entry(list_id).or_default();
+
+        // Log if client already exists (shouldn't happen normally)
+        if sessions.connections.contains_key(&presence.connection_id) {
+            println!("WARNING: Client {} already exists in sessions", presence.connection_id);
+        }
+
+        sessions
+            .people
+            .entry(presence.source_id.clone())
+            .or_default()
+            .insert(presence.connection_id.clone());
+        sessions.connections.insert(presence.connection_id.clone(), presence);
+        sessions.headcount()
+    }
+
+    // Remove a client from a list, returning the new count and the todo it was editing
+    pub fn remove_client(&self, list_id: i64, connection_id: &str) -> (Headcount, Option<i64>) {
+        let mut lists = self.lists.lock().unwrap();
+        let sessions = lists.entry(list_id).or_default();
+
+        // Log if we're trying to remove a non-existent client
+        let editing = match sessions.connections.remove(connection_id) {
+            Some(presence) => {
+                if let Some(tabs) = sessions.people.get_mut(&presence.source_id) {
+                    tabs.remove(connection_id);
+                    if tabs.is_empty() {
+                        sessions.people.remove(&presence.source_id);
+                    }
+                }
+                presence.editing
+            }
+            None => {
+                println!("WARNING: Trying to remove non-existent client {}", connection_id);
+                None
+            }
+        };
+        sessions.locks.retain(|_, lock| lock.connection_id != connection_id);
+
+        let count = sessions.headcount();
+        if count.connections == 0 {
+            lists.remove(&list_id);
+        }
+        self.streams.lock().unwrap().remove(connection_id);
+        (count, editing)
+    }
+
+    // Start keeping state for an event stream's connection, which has been sent up to `seq`
+    pub fn open_stream(&self, connection_id: &str, seq: u64, cursor_interval: Duration) -> Arc<StreamState> {
+        let state = Arc::new(StreamState {
+            cursor: AsyncMutex::new(CursorThrottle::new(cursor_interval)),
+            last_sent_seq: AtomicU64::new(seq),
+            cursor_held: Notify::new(),
+        });
+        self.streams.lock().unwrap().insert(connection_id.to_string(), state.clone());
+        state
+    }
+
+    pub fn stream(&self, connection_id: &str) -> Option<Arc<StreamState>> {
+        self.streams.lock().unwrap().get(connection_id).cloned()
+    }
+
+    pub fn presence(&self, list_id: i64, connection_id: &str) -> Option<Presence> {
+        let lists = self.lists.lock().unwrap();
+        lists.get(&list_id)?.connections.get(connection_id).cloned()
+    }
+
+    // A connection on any list, with the list it's on
+    pub fn find(&self, connection_id: &str) -> Option<(i64, Presence)> {
+        let lists = self.lists.lock().unwrap();
+        lists
+            .iter()
+            .find_map(|(list_id, sessions)| Some((*list_id, sessions.connections.get(connection_id)?.clone())))
+    }
+
+    // Everyone connected to a list, for the init snapshot
+    pub fn snapshot(&self, list_id: i64) -> Vec<Presence> {
+        let lists = self.lists.lock().unwrap();
+        lists
+            .get(&list_id)
+            .map(|sessions| sessions.connections.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Put a connection into edit mode on a todo, optionally taking its lock.
+    // Returns the updated presence and the todo it was editing before.
+    pub fn start_editing(&self, list_id: i64, connection_id: &str, todo_id: i64, lock: bool) -> Result<(Presence, Option<i64>), EditRefused> {
+        let mut lists = self.lists.lock().unwrap();
+        let Some(sessions) = lists.get_mut(&list_id) else {
+            return Err(EditRefused::NotConnected);
+        };
+        let Some(source_id) = sessions.connections.get(connection_id).map(|presence| presence.source_id.clone()) else {
+            return Err(EditRefused::NotConnected);
+        };
+        let now = Instant::now();
+        sessions.locks.retain(|_, lock| lock.expires_at > now);
+
+        // The same person in another tab isn't locked out
+        if let Some(held) = sessions.locks.get(&todo_id) {
+            if held.source_id != source_id {
+                if let Some(holder) = sessions.connections.get(&held.connection_id) {
+                    return Err(EditRefused::Locked(Box::new(holder.clone()), held.expires_at - now));
+                }
+            }
+        }
+
+        if lock {
+            sessions.locks.insert(todo_id, EditLock {
+                connection_id: connection_id.to_string(),
+                source_id,
+                expires_at: now + self.lock_timeout,
+            });
+        }
+        let presence = sessions.connections.get_mut(connection_id).expect("connection is registered");
+        let previous = presence.editing.replace(todo_id).filter(|previous| *previous != todo_id);
+        if let Some(previous) = previous {
+            release(&mut sessions.locks, previous, connection_id);
+        }
+        Ok((presence.clone(), previous))
+    }
+
+    // Leave edit mode; returns whether the connection was editing that todo
+    pub fn stop_editing(&self, list_id: i64, connection_id: &str, todo_id: i64) -> bool {
+        let mut lists = self.lists.lock().unwrap();
+        let Some(sessions) = lists.get_mut(&list_id) else {
+            return false;
+        };
+        release(&mut sessions.locks, todo_id, connection_id);
+        match sessions.connections.get_mut(connection_id) {
+            Some(presence) if presence.editing == Some(todo_id) => {
+                presence.editing = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Activity on a locked todo keeps the lock alive
+    pub fn refresh_lock(&self, list_id: i64, connection_id: &str, todo_id: i64) {
+        let mut lists = self.lists.lock().unwrap();
+        let lock = lists
+            .get_mut(&list_id)
+            .and_then(|sessions| sessions.locks.get_mut(&todo_id))
+            .filter(|lock| lock.connection_id == connection_id);
+        if let Some(lock) = lock {
+            lock.expires_at = Instant::now() + self.lock_timeout;
+        }
+    }
+
+    // Debug print all sessions
+    pub fn debug_print(&self) {
+        let lists = self.lists.lock().unwrap();
+        for (list_id, sessions) in lists.iter() {
+            let count = sessions.headcount();
+            println!("DEBUG: Current sessions for list {} ({} people, {} connections):", list_id, count.people, count.connections);
+            for (source_id, tabs) in &sessions.people {
+                println!("  - {}:", source_id);
+                for presence in tabs.iter().filter_map(|id| sessions.connections.get(id)) {
+                    println!("      {} ({}, editing {:?})", presence.connection_id, presence.display_name, presence.editing);
+                }
+            }
+        }
+    }
+}
+
+fn release(locks: &mut HashMap<i64, EditLock>, todo_id: i64, connection_id: &str) {
+    if locks.get(&todo_id).is_some_and(|lock| lock.connection_id == connection_id) {
+        locks.remove(&todo_id);
+    }
+}
+
+// Relays at most one cursor per interval for a connection, always ending on its latest position
+pub struct CursorThrottle {
+    interval: Duration,
+    last_sent: Option<TokioInstant>,
+    pending: Option<ServerMessage>,
+}
+
+impl CursorThrottle {
+    pub fn new(interval: Duration) -> Self {
+        CursorThrottle {
+            interval,
+            last_sent: None,
+            pending: None,
+        }
+    }
+
+    // The cursor if it may go out now; otherwise it's held until `deadline`
+    pub fn offer(&mut self, cursor: ServerMessage) -> Option<ServerMessage> {
+        let now = TokioInstant::now();
+        if self.last_sent.is_some_and(|sent| now < sent + self.interval) {
+            self.pending = Some(cursor);
+            return None;
+        }
+        self.last_sent = Some(now);
+        self.pending = None;
+        Some(cursor)
+    }
+
+    // When the held cursor is due, if there is one
+    pub fn deadline(&self) -> Option<TokioInstant> {
+        self.pending.as_ref()?;
+        self.last_sent.map(|sent| sent + self.interval)
+    }
+
+    pub fn take_due(&mut self) -> Option<ServerMessage> {
+        self.last_sent = Some(TokioInstant::now());
+        self.pending.take()
+    }
+
+    // Drop a held cursor, e.g. once its todo has left edit mode
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+}
+
+// Removes a connection from the sessions and its editors, and announces the new count, when dropped
+pub struct SessionGuard<'a> {
+    pub sessions: &'a ClientSessions,
+    pub titles: &'a TitleDocs,
+    pub queue: &'a Broadcaster,
+    pub list_id: i64,
+    pub ws_client_id: String,
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        // Remove this client from active sessions and get updated count
+        let (count, editing) = self.sessions.remove_client(self.list_id, &self.ws_client_id);
+        println!("WebSocket session ended: {}. Connected users: {} ({} connections)", self.ws_client_id, count.people, count.connections);
+
+        // Debug print sessions after disconnect
+        self.sessions.debug_print();
+
+        // Whatever it had open is no longer being edited
+        self.titles.leave(&self.ws_client_id);
+        if let Some(todo_id) = editing {
+            self.queue.publish(ServerMessage::EditingStopped { todo_id, connection_id: self.ws_client_id.clone() }, None);
+        }
+
+        // Broadcast user count update
+        self.queue.publish(ServerMessage::UserCount { connected_users: count.people, connections: count.connections }, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> ClientSessions {
+        let heartbeat = HeartbeatConfig { ping_interval: Duration::from_secs(20), pong_timeout: Duration::from_secs(10) };
+        ClientSessions::new(Duration::from_secs(30), heartbeat)
+    }
+
+    fn presence(connection_id: &str, user_id: i64) -> Presence {
+        let user = User { id: user_id, username: Some(format!("user{}", user_id)), fid: None, farcaster_username: None };
+        Presence::new(connection_id.to_string(), &user)
+    }
+
+    #[test]
+    fn editing_after_leaving_is_refused() {
+        let sessions = sessions();
+        sessions.add_client(1, presence("a", 1));
+        sessions.add_client(1, presence("b", 2));
+        sessions.remove_client(1, "a");
+        assert!(matches!(sessions.start_editing(1, "a", 7, true), Err(EditRefused::NotConnected)));
+
+        // The last one out removes the list, and it stays removed
+        sessions.remove_client(1, "b");
+        assert!(matches!(sessions.start_editing(1, "b", 7, true), Err(EditRefused::NotConnected)));
+        assert!(sessions.lists.lock().unwrap().is_empty());
+
+        // And nothing was poisoned along the way
+        sessions.add_client(1, presence("c", 3));
+        assert!(sessions.start_editing(1, "c", 7, true).is_ok());
+    }
+
+    #[test]
+    fn locks_keep_other_people_out() {
+        let sessions = sessions();
+        sessions.add_client(1, presence("a", 1));
+        sessions.add_client(1, presence("b", 2));
+        sessions.add_client(1, presence("a2", 1));
+        sessions.start_editing(1, "a", 7, true).unwrap();
+        assert!(matches!(sessions.start_editing(1, "b", 7, true), Err(EditRefused::Locked(holder, _)) if holder.connection_id == "a"));
+        assert!(sessions.start_editing(1, "a2", 7, true).is_ok());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::db::Todo;
+use crate::presence::Presence;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Messages a client may send over /todo-ws
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClientMessage {
+    // Enter edit mode: fetches the title's collaborative document and, with
+    // `lock`, keeps other people out of edit mode until the lock times out
+    EditOpen {
+        todo_id: i64,
+        #[serde(default)]
+        lock: bool,
+    },
+    // Incremental title edit: a base64 Yjs update, merged and saved by the server
+    EditUpdate {
+        todo_id: i64,
+        update: String,
+    },
+    // Done editing; tells every client to show the merged title. `content` is
+    // what this client ended up with, checked against the server's copy.
+    SaveEdit {
+        todo_id: i64,
+        content: Option<String>,
+    },
+    // Caret or selection in a todo this client is editing, in UTF-16 offsets
+    Cursor {
+        todo_id: i64,
+        start: u32,
+        end: u32,
+    },
+    // Reorder a todo: it goes right after `before` and right before `after`,
+    // either left out at that end of the list
+    Move {
+        todo_id: i64,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+    // Replay everything broadcast after `last_seq` (falls back to a resync)
+    Resume {
+        last_seq: u64,
+        epoch: u32,
+    },
+}
+
+// Messages the server sends, either broadcast or to a single socket
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerMessage {
+    // Snapshot sent right after connecting
+    Init {
+        // This connection, for telling our own presence apart and for posting messages over HTTP
+        connection_id: String,
+        // Left out when an event stream resumes, since the replay or resync after it has them
+        #[serde(skip_serializing_if = "Option::is_none")]
+        todos: Option<Vec<Todo>>,
+        // Distinct people, however many tabs each has open
+        connected_users: usize,
+        connections: usize,
+        presence: Vec<Presence>,
+        seq: u64,
+        epoch: u32,
+    },
+    // Sent after replaying missed events for a resume
+    Resumed {
+        seq: u64,
+    },
+    // Full snapshot sent when a resume can't be served from history
+    Resync {
+        todos: Vec<Todo>,
+        seq: u64,
+        epoch: u32,
+    },
+    // Add, update and toggle carry the todo's card rendered once by the server,
+    // so clients can swap it in without fetching /todo-read themselves
+    Add {
+        todo_id: i64,
+        // Set for subtasks, which are drawn inside their parent's card
+        #[serde(skip_serializing_if = "Option::is_none")]
+        parent_id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        html: Option<String>,
+    },
+    Update {
+        todo_id: i64,
+        content: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        html: Option<String>,
+    },
+    Toggle {
+        todo_id: i64,
+        completed: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        html: Option<String>,
+    },
+    Delete {
+        todo_id: i64,
+    },
+    // A todo was reordered. `position` sorts (bytewise) among the positions of the
+    // other todos; `before` and `after` are the neighbors it was dropped between.
+    Move {
+        todo_id: i64,
+        position: String,
+        before: Option<i64>,
+        after: Option<i64>,
+    },
+    // A todo's reminder time has come
+    Reminder {
+        todo_id: i64,
+        title: String,
+        due_at: Option<DateTime<Utc>>,
+    },
+    // Reply to edit_open: the full title document as a base64 Yjs update
+    EditState {
+        todo_id: i64,
+        update: String,
+    },
+    // A merged title edit, with the resulting title for clients not editing it
+    EditUpdate {
+        todo_id: i64,
+        update: String,
+        content: String,
+    },
+    UserCount {
+        connected_users: usize,
+        connections: usize,
+    },
+    EditingStarted {
+        todo_id: i64,
+        user: Box<Presence>,
+    },
+    EditingStopped {
+        todo_id: i64,
+        connection_id: String,
+    },
+    // Where a collaborator's caret is; relayed, never saved
+    Cursor {
+        todo_id: i64,
+        start: u32,
+        end: u32,
+        user: Box<Presence>,
+    },
+    // Reply to edit_open when someone else holds the todo's lock
+    Locked {
+        todo_id: i64,
+        user: Box<Presence>,
+        expires_in_ms: u64,
+    },
+    // Reply to a save_edit from this socket
+    EditSaved {
+        todo_id: i64,
+        success: bool,
+    },
+    // Reply to a save_edit based on a stale view, with the title as the server has it
+    Conflict {
+        todo_id: i64,
+        content: String,
+        version: i64,
+    },
+    // Reply to a message the server could not handle
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Not valid JSON
+    Malformed,
+    // Valid JSON, but an unknown event or missing fields
+    Invalid,
+    // Binary or other non-text frames
+    Unsupported,
+    // No such todo in this connection's list, or no such connection
+    NotFound,
+    // The server failed to apply the message
+    Failed,
+}
+
+impl ServerMessage {
+    // Changes to the todo list and reminders; live keystrokes and presence are not worth replaying
+    pub fn is_replayable(&self) -> bool {
+        matches!(
+            self,
+            ServerMessage::Add { .. }
+                | ServerMessage::Update { .. }
+                | ServerMessage::Toggle { .. }
+                | ServerMessage::Delete { .. }
+                | ServerMessage::Move { .. }
+                | ServerMessage::Reminder { .. }
+        )
+    }
+
+    // The event as out-of-band swaps for the htmx `ws` extension, which swaps each
+    // top-level element by id unless it says otherwise with `hx-swap-oob`
+    pub fn htmx(&self) -> Option<String> {
+        match self {
+            // A subtask arrives with the update to its top-level card instead
+            ServerMessage::Add { parent_id: None, html: Some(html), .. } => {
+                Some(format!(r#"<div hx-swap-oob="afterbegin:#todo-cards">{}</div>"#, html))
+            }
+            ServerMessage::Update { html: Some(html), .. } | ServerMessage::Toggle { html: Some(html), .. } => Some(html.clone()),
+            ServerMessage::Delete { todo_id } => Some(format!(r#"<div id="todo_{}" hx-swap-oob="delete"></div>"#, todo_id)),
+            ServerMessage::UserCount { connected_users, .. } => {
+                Some(format!(r#"<span id="user-count" hx-swap-oob="true">{} Online</span>"#, connected_users))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Envelope for everything sent through the broadcast channel
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TodoUpdate {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+}
+
+impl ClientMessage {
+    // Parse a text frame, turning failures into the error reply to send back
+    pub fn parse(text: &str) -> Result<Self, ServerMessage> {
+        serde_json::from_str(text).map_err(|e| {
+            let code = if e.is_data() {
+                ErrorCode::Invalid
+            } else {
+                ErrorCode::Malformed
+            };
+            ServerMessage::Error {
+                code,
+                message: e.to_string(),
+            }
+        })
+    }
+}
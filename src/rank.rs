@@ -0,0 +1,218 @@
+// Fractional ranks for manual ordering: strings that sort (bytewise) in list order,
+// with a new one always available between any two. Moving a todo rewrites only
+// its own rank. Keys are an integer part (a head letter giving its length, then
+// digits) and an optional fraction, so repeated adds at either end stay short.
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// The rank of the first todo in an empty list
+const ZERO: &str = "a0";
+
+fn digit(index: usize) -> char {
+    DIGITS[index] as char
+}
+
+fn index_of(c: u8) -> Option<usize> {
+    DIGITS.iter().position(|d| *d == c)
+}
+
+// A rank strictly between `before` and `after` (either may be open), or None if
+// they are malformed or out of order
+pub fn between(before: Option<&str>, after: Option<&str>) -> Option<String> {
+    if let (Some(a), Some(b)) = (before, after) {
+        if a >= b {
+            return None;
+        }
+    }
+
+    match (before, after) {
+        (None, None) => Some(ZERO.to_string()),
+        (None, Some(b)) => {
+            let (int_b, frac_b) = split(b)?;
+            if int_b.len() < b.len() {
+                // Dropping b's fraction gives a smaller key
+                return Some(int_b.to_string());
+            }
+            match decrement(int_b) {
+                Some(int) => Some(int),
+                None => Some(format!("{}{}", int_b, midpoint("", Some(frac_b))?)),
+            }
+        }
+        (Some(a), None) => {
+            let (int_a, frac_a) = split(a)?;
+            match increment(int_a) {
+                Some(int) => Some(int),
+                None => Some(format!("{}{}", int_a, midpoint(frac_a, None)?)),
+            }
+        }
+        (Some(a), Some(b)) => {
+            let (int_a, frac_a) = split(a)?;
+            let (int_b, frac_b) = split(b)?;
+            if int_a == int_b {
+                return Some(format!("{}{}", int_a, midpoint(frac_a, Some(frac_b))?));
+            }
+            match increment(int_a) {
+                Some(int) if int.as_str() < b => Some(int),
+                _ => Some(format!("{}{}", int_a, midpoint(frac_a, None)?)),
+            }
+        }
+    }
+}
+
+// Length of the integer part a head letter announces: a-z for 2 to 27
+// characters going up, A-Z for 2 to 27 going down
+fn integer_length(head: u8) -> Option<usize> {
+    match head {
+        b'a'..=b'z' => Some((head - b'a') as usize + 2),
+        b'A'..=b'Z' => Some((b'Z' - head) as usize + 2),
+        _ => None,
+    }
+}
+
+// The integer part and fraction of a key. Fractions never end in the zero digit,
+// or there would be nothing left between them and the shorter key.
+fn split(key: &str) -> Option<(&str, &str)> {
+    let length = integer_length(*key.as_bytes().first()?)?;
+    if key.len() < length || !key.bytes().skip(1).all(|c| index_of(c).is_some()) {
+        return None;
+    }
+    let (int, frac) = key.split_at(length);
+    if frac.ends_with(digit(0)) {
+        return None;
+    }
+    Some((int, frac))
+}
+
+// The next integer part, or None past the largest
+fn increment(int: &str) -> Option<String> {
+    let head = int.as_bytes()[0];
+    let mut digits: Vec<usize> = int.bytes().skip(1).filter_map(index_of).collect();
+    for d in digits.iter_mut().rev() {
+        if *d + 1 < DIGITS.len() {
+            *d += 1;
+            return Some(join(head, &digits));
+        }
+        *d = 0;
+    }
+
+    // Every digit carried over, so move to the next length
+    match head {
+        b'Z' => Some(format!("a{}", digit(0))),
+        b'z' => None,
+        _ => {
+            let head = head + 1;
+            if head > b'a' {
+                digits.push(0);
+            } else {
+                digits.pop();
+            }
+            Some(join(head, &digits))
+        }
+    }
+}
+
+// The previous integer part, or None before the smallest
+fn decrement(int: &str) -> Option<String> {
+    let head = int.as_bytes()[0];
+    let max = DIGITS.len() - 1;
+    let mut digits: Vec<usize> = int.bytes().skip(1).filter_map(index_of).collect();
+    for d in digits.iter_mut().rev() {
+        if *d > 0 {
+            *d -= 1;
+            return Some(join(head, &digits));
+        }
+        *d = max;
+    }
+
+    match head {
+        b'a' => Some(format!("Z{}", digit(max))),
+        b'A' => None,
+        _ => {
+            let head = head - 1;
+            if head < b'Z' {
+                digits.push(max);
+            } else {
+                digits.pop();
+            }
+            Some(join(head, &digits))
+        }
+    }
+}
+
+fn join(head: u8, digits: &[usize]) -> String {
+    std::iter::once(head as char).chain(digits.iter().map(|d| digit(*d))).collect()
+}
+
+// A fraction strictly between two others, `after` open-ended when None
+fn midpoint(before: &str, after: Option<&str>) -> Option<String> {
+    let a = before.as_bytes();
+    if let Some(after) = after {
+        let b = after.as_bytes();
+        // Keep the shared prefix; a missing digit of `before` counts as zero
+        let common = (0..b.len()).take_while(|&n| a.get(n).copied().unwrap_or(DIGITS[0]) == b[n]).count();
+        if common > 0 {
+            let rest = midpoint(before.get(common..).unwrap_or(""), Some(&after[common..]))?;
+            return Some(format!("{}{}", &after[..common], rest));
+        }
+    }
+
+    let digit_a = match a.first() {
+        Some(c) => index_of(*c)?,
+        None => 0,
+    };
+    let digit_b = match after.and_then(|b| b.as_bytes().first()) {
+        Some(c) => index_of(*c)?,
+        None => DIGITS.len(),
+    };
+    if digit_b - digit_a > 1 {
+        return Some(digit((digit_a + digit_b).div_ceil(2)).to_string());
+    }
+
+    // Adjacent digits: the first digit of a longer `after` fits, or go one digit deeper
+    match after {
+        Some(b) if b.len() > 1 => Some(b[..1].to_string()),
+        _ => Some(format!("{}{}", digit(digit_a), midpoint(before.get(1..).unwrap_or(""), None)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::between;
+
+    #[test]
+    fn ranks_fall_between_their_neighbors() {
+        let first = between(None, None).unwrap();
+        let last = between(Some(&first), None).unwrap();
+        let middle = between(Some(&first), Some(&last)).unwrap();
+        let top = between(None, Some(&first)).unwrap();
+        assert!(top < first && first < middle && middle < last);
+        assert_eq!(between(Some(&last), Some(&first)), None);
+    }
+
+    #[test]
+    fn repeated_inserts_stay_ordered_and_short() {
+        // Adding at the top, like new todos
+        let mut top = between(None, None).unwrap();
+        for _ in 0..1000 {
+            let next = between(None, Some(&top)).unwrap();
+            assert!(next < top);
+            top = next;
+        }
+        assert!(top.len() <= 3, "{}", top);
+
+        // Squeezing into the same gap over and over
+        let (low, mut high) = ("a0".to_string(), "a1".to_string());
+        for _ in 0..100 {
+            let next = between(Some(&low), Some(&high)).unwrap();
+            assert!(low < next && next < high);
+            high = next;
+        }
+    }
+
+    #[test]
+    fn migrated_ranks_are_valid() {
+        // The migration numbers existing todos e00000, e00001, ...
+        let rank = between(None, Some("e00000")).unwrap();
+        assert!(rank.as_str() < "e00000");
+        assert_eq!(between(Some("e00041"), Some("e00042")).unwrap(), "e00041V");
+    }
+}
@@ -0,0 +1,465 @@
+// Recurring todos: the subset of RFC 5545 recurrence rules we support, and stepping
+// a due date to its next occurrence. Occurrences keep the local time of day of the
+// one before, so a chore due at 9:00 stays due at 9:00 across DST changes.
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc, Weekday};
+use rocket::form::{self, FromFormField, ValueField};
+use std::fmt;
+
+// Periods to look through for a matching day before giving up on a rule that never
+// matches, like the 30th of every February
+const MAX_PERIODS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+// The last occurrence allowed: through a whole local date, or up to an instant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Until {
+    Date(NaiveDate),
+    At(DateTime<Utc>),
+}
+
+// FREQ, INTERVAL, BYDAY, BYMONTHDAY, UNTIL and COUNT; weeks start on Monday
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub freq: Freq,
+    pub interval: u32,
+    // Weekdays, with an ordinal in monthly rules: 2TU is the second Tuesday, -1FR the last Friday
+    pub by_day: Vec<(Option<i8>, Weekday)>,
+    // Days of the month, negative counting from the end; monthly rules only
+    pub by_month_day: Vec<i8>,
+    pub until: Option<Until>,
+    // Occurrences left, this one included
+    pub count: Option<u32>,
+}
+
+impl Rule {
+    // Reads `FREQ=WEEKLY;BYDAY=MO,WE`, with or without a leading `RRULE:`
+    pub fn parse(input: &str) -> Result<Rule, String> {
+        let input = input.trim();
+        let input = match input.get(..6) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("RRULE:") => &input[6..],
+            _ => input,
+        };
+        let mut freq = None;
+        let mut rule = Rule {
+            freq: Freq::Daily,
+            interval: 1,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            until: None,
+            count: None,
+        };
+
+        for part in input.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_once('=').ok_or_else(|| format!("Expected NAME=VALUE, got {:?}", part))?;
+            let value = value.to_ascii_uppercase();
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.as_str() {
+                        "DAILY" => Freq::Daily,
+                        "WEEKLY" => Freq::Weekly,
+                        "MONTHLY" => Freq::Monthly,
+                        "YEARLY" => Freq::Yearly,
+                        _ => return Err(format!("FREQ must be DAILY, WEEKLY, MONTHLY or YEARLY, not {}", value)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value.parse().ok().filter(|n| *n >= 1).ok_or("INTERVAL must be a whole number from 1")?;
+                }
+                "BYDAY" => rule.by_day = value.split(',').map(parse_day).collect::<Result<_, _>>()?,
+                "BYMONTHDAY" => {
+                    rule.by_month_day = value
+                        .split(',')
+                        .map(|day| day.parse().ok().filter(|day: &i8| *day != 0 && day.abs() <= 31))
+                        .collect::<Option<_>>()
+                        .ok_or("BYMONTHDAY days must be from 1 to 31, or -1 to -31 from the end")?;
+                }
+                "UNTIL" => rule.until = Some(parse_until(&value)?),
+                "COUNT" => rule.count = Some(value.parse().ok().filter(|n| *n >= 1).ok_or("COUNT must be a whole number from 1")?),
+                // The only week start we use anyway
+                "WKST" if value == "MO" => {}
+                other => return Err(format!("{} isn't supported", other)),
+            }
+        }
+
+        rule.freq = freq.ok_or("FREQ is required")?;
+        if rule.until.is_some() && rule.count.is_some() {
+            return Err("Use UNTIL or COUNT, not both".to_string());
+        }
+        if rule.freq != Freq::Monthly && rule.by_day.iter().any(|(ordinal, _)| ordinal.is_some()) {
+            return Err("Numbered weekdays like 2TU only go with FREQ=MONTHLY".to_string());
+        }
+        if rule.freq != Freq::Monthly && !rule.by_month_day.is_empty() {
+            return Err("BYMONTHDAY only goes with FREQ=MONTHLY".to_string());
+        }
+        if rule.freq == Freq::Yearly && !rule.by_day.is_empty() {
+            return Err("BYDAY doesn't go with FREQ=YEARLY".to_string());
+        }
+        Ok(rule)
+    }
+
+    // The occurrence after one due at `due`, as seen in `tz`, and the rule for the
+    // occurrence after that (COUNT goes down by one). None once the rule runs out.
+    pub fn next<Tz: TimeZone>(&self, due: DateTime<Utc>, tz: &Tz) -> Option<(DateTime<Utc>, Rule)> {
+        if self.count == Some(1) {
+            return None;
+        }
+        let start = due.with_timezone(tz).naive_local();
+        let date = self.next_date(start.date())?;
+        let at = resolve(tz, date.and_time(start.time()))?;
+        let allowed = match self.until {
+            Some(Until::Date(until)) => date <= until,
+            Some(Until::At(until)) => at <= until,
+            None => true,
+        };
+        if !allowed {
+            return None;
+        }
+
+        let rule = Rule {
+            count: self.count.map(|count| count - 1),
+            ..self.clone()
+        };
+        Some((at, rule))
+    }
+
+    // The first occurrence after both `due` and `now`, so a todo finished late doesn't
+    // come back already overdue. Occurrences skipped on the way still count for COUNT.
+    pub fn upcoming<Tz: TimeZone>(&self, due: DateTime<Utc>, now: DateTime<Utc>, tz: &Tz) -> Option<(DateTime<Utc>, Rule)> {
+        let (mut at, mut rule) = self.next(due, tz)?;
+        while at <= now {
+            (at, rule) = rule.next(at, tz)?;
+        }
+        Some((at, rule))
+    }
+
+    // The first day after `after` the rule lands on, counting periods from the one
+    // `after` is in
+    fn next_date(&self, after: NaiveDate) -> Option<NaiveDate> {
+        (0..MAX_PERIODS).find_map(|period| {
+            let step = period.checked_mul(self.interval)?;
+            self.dates_in_period(after, step).into_iter().find(|date| *date > after)
+        })
+    }
+
+    // The days, in order, the rule lands on in the period `step` periods after the one
+    // `anchor` is in
+    fn dates_in_period(&self, anchor: NaiveDate, step: u32) -> Vec<NaiveDate> {
+        let weekdays: Vec<Weekday> = self.by_day.iter().map(|(_, weekday)| *weekday).collect();
+        match self.freq {
+            Freq::Daily => anchor
+                .checked_add_days(chrono::Days::new(step.into()))
+                .filter(|date| weekdays.is_empty() || weekdays.contains(&date.weekday()))
+                .into_iter()
+                .collect(),
+            Freq::Weekly => {
+                let monday = anchor.week(Weekday::Mon).first_day();
+                let weekdays = if weekdays.is_empty() { vec![anchor.weekday()] } else { weekdays };
+                let mut dates: Vec<NaiveDate> = weekdays
+                    .iter()
+                    .filter_map(|weekday| {
+                        let offset = u64::from(step) * 7 + u64::from(weekday.num_days_from_monday());
+                        monday.checked_add_days(chrono::Days::new(offset))
+                    })
+                    .collect();
+                dates.sort();
+                dates
+            }
+            Freq::Monthly => {
+                let month = i64::from(anchor.year()) * 12 + i64::from(anchor.month0()) + i64::from(step);
+                let Some(first) = i32::try_from(month.div_euclid(12))
+                    .ok()
+                    .and_then(|year| NaiveDate::from_ymd_opt(year, month.rem_euclid(12) as u32 + 1, 1))
+                else {
+                    return Vec::new();
+                };
+                let days: Vec<NaiveDate> = first.iter_days().take_while(|date| date.month() == first.month()).collect();
+                self.days_of_month(&days, anchor.day())
+            }
+            Freq::Yearly => i32::try_from(step)
+                .ok()
+                .and_then(|step| anchor.year().checked_add(step))
+                .and_then(|year| NaiveDate::from_ymd_opt(year, anchor.month(), anchor.day()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    // Which of a month's days a monthly rule picks: those matching both BYMONTHDAY and
+    // BYDAY when given, otherwise the day of the month the rule started on. Months
+    // without that day are skipped, as RFC 5545 says.
+    fn days_of_month(&self, days: &[NaiveDate], anchor_day: u32) -> Vec<NaiveDate> {
+        let length = days.len() as i64;
+        let by_month_day: Vec<NaiveDate> = self
+            .by_month_day
+            .iter()
+            .filter_map(|day| {
+                let day = i64::from(*day);
+                let index = if day > 0 { day - 1 } else { length + day };
+                usize::try_from(index).ok().and_then(|index| days.get(index).copied())
+            })
+            .collect();
+        let by_day: Vec<NaiveDate> = self
+            .by_day
+            .iter()
+            .flat_map(|(ordinal, weekday)| {
+                let matching: Vec<NaiveDate> = days.iter().copied().filter(|date| date.weekday() == *weekday).collect();
+                match ordinal {
+                    Some(n) if *n > 0 => matching.get(*n as usize - 1).copied().into_iter().collect(),
+                    Some(n) => matching.len().checked_sub(n.unsigned_abs() as usize).and_then(|index| matching.get(index).copied()).into_iter().collect(),
+                    None => matching,
+                }
+            })
+            .collect();
+
+        let mut dates: Vec<NaiveDate> = match (self.by_month_day.is_empty(), self.by_day.is_empty()) {
+            (true, true) => days.get(anchor_day as usize - 1).copied().into_iter().collect(),
+            (false, true) => by_month_day,
+            (true, false) => by_day,
+            (false, false) => by_month_day.into_iter().filter(|date| by_day.contains(date)).collect(),
+        };
+        dates.sort();
+        dates.dedup();
+        dates
+    }
+}
+
+// Stored and shown in its RFC 5545 form, without the `RRULE:` prefix
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let freq = match self.freq {
+            Freq::Daily => "DAILY",
+            Freq::Weekly => "WEEKLY",
+            Freq::Monthly => "MONTHLY",
+            Freq::Yearly => "YEARLY",
+        };
+        write!(f, "FREQ={}", freq)?;
+        if self.interval > 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self
+                .by_day
+                .iter()
+                .map(|(ordinal, weekday)| format!("{}{}", ordinal.map(|n| n.to_string()).unwrap_or_default(), day_code(*weekday)))
+                .collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if !self.by_month_day.is_empty() {
+            let days: Vec<String> = self.by_month_day.iter().map(i8::to_string).collect();
+            write!(f, ";BYMONTHDAY={}", days.join(","))?;
+        }
+        match self.until {
+            Some(Until::Date(date)) => write!(f, ";UNTIL={}", date.format("%Y%m%d"))?,
+            Some(Until::At(at)) => write!(f, ";UNTIL={}", at.format("%Y%m%dT%H%M%SZ"))?,
+            None => {}
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        Ok(())
+    }
+}
+
+const DAY_CODES: [(&str, Weekday); 7] = [
+    ("MO", Weekday::Mon),
+    ("TU", Weekday::Tue),
+    ("WE", Weekday::Wed),
+    ("TH", Weekday::Thu),
+    ("FR", Weekday::Fri),
+    ("SA", Weekday::Sat),
+    ("SU", Weekday::Sun),
+];
+
+fn day_code(weekday: Weekday) -> &'static str {
+    DAY_CODES.iter().find(|(_, day)| *day == weekday).map_or("MO", |(code, _)| code)
+}
+
+// `MO`, or with an ordinal, `2TU`, `+1WE` or `-1FR`
+fn parse_day(value: &str) -> Result<(Option<i8>, Weekday), String> {
+    let invalid = || format!("Can't read {:?} as a weekday like MO or -1FR", value);
+    let split = value.len().checked_sub(2).filter(|at| value.is_char_boundary(*at)).ok_or_else(invalid)?;
+    let (ordinal, code) = value.split_at(split);
+    let weekday = DAY_CODES.iter().find(|(day, _)| *day == code).map(|(_, weekday)| *weekday).ok_or_else(invalid)?;
+    if ordinal.is_empty() {
+        return Ok((None, weekday));
+    }
+    let ordinal: i8 = ordinal.parse().ok().filter(|n: &i8| *n != 0 && n.abs() <= 5).ok_or_else(invalid)?;
+    Ok((Some(ordinal), weekday))
+}
+
+// `20250301` for the end of that day, or `20250301T170000Z`
+fn parse_until(value: &str) -> Result<Until, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok(Until::Date(date));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ")
+        .map(|at| Until::At(at.and_utc()))
+        .map_err(|_| format!("UNTIL must look like 20250301 or 20250301T170000Z, not {}", value))
+}
+
+// A local time as an instant. Ambiguous times (when clocks fall back) take the
+// first; times skipped when clocks spring forward move on by the length of the gap.
+fn resolve<Tz: TimeZone>(tz: &Tz, at: NaiveDateTime) -> Option<DateTime<Utc>> {
+    tz.from_local_datetime(&at)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(at + TimeDelta::hours(1))).earliest())
+        .map(|at| at.with_timezone(&Utc))
+}
+
+// A recurrence field of a create or edit: left out, cleared, or set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Repeat {
+    #[default]
+    Unchanged,
+    Clear,
+    Every(Rule),
+}
+
+impl Repeat {
+    // Blank clears
+    pub fn parse(input: &str) -> Result<Repeat, String> {
+        if input.trim().is_empty() {
+            return Ok(Repeat::Clear);
+        }
+        Rule::parse(input).map(Repeat::Every)
+    }
+
+    // The stored rule after applying this change to `current`
+    pub fn apply(self, current: Option<String>) -> Option<String> {
+        match self {
+            Repeat::Unchanged => current,
+            Repeat::Clear => None,
+            Repeat::Every(rule) => Some(rule.to_string()),
+        }
+    }
+}
+
+impl<'v> FromFormField<'v> for Repeat {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Repeat::parse(field.value).map_err(|message| form::Error::validation(message).into())
+    }
+
+    // A form without the field leaves the todo's rule alone
+    fn default() -> Option<Self> {
+        Some(Repeat::Unchanged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Repeat, Rule};
+    use chrono::{DateTime, TimeZone, Utc};
+    use chrono_tz::America::New_York;
+
+    // A New York wall-clock time
+    fn ny(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        New_York.with_ymd_and_hms(y, m, d, h, min, 0).earliest().unwrap().with_timezone(&Utc)
+    }
+
+    // The next `n` occurrences after `due`
+    fn occurrences(rule: &str, due: DateTime<Utc>, n: usize) -> Vec<DateTime<Utc>> {
+        let mut rule = Rule::parse(rule).unwrap();
+        let mut at = due;
+        let mut found = Vec::new();
+        while found.len() < n {
+            let Some((next, next_rule)) = rule.next(at, &New_York) else {
+                break;
+            };
+            found.push(next);
+            (at, rule) = (next, next_rule);
+        }
+        found
+    }
+
+    #[test]
+    fn daily() {
+        assert_eq!(occurrences("FREQ=DAILY", ny(2024, 1, 30, 9, 0), 3), vec![ny(2024, 1, 31, 9, 0), ny(2024, 2, 1, 9, 0), ny(2024, 2, 2, 9, 0)]);
+        assert_eq!(occurrences("FREQ=DAILY;INTERVAL=3", ny(2024, 2, 27, 9, 0), 2), vec![ny(2024, 3, 1, 9, 0), ny(2024, 3, 4, 9, 0)]);
+    }
+
+    #[test]
+    fn weekly() {
+        assert_eq!(occurrences("FREQ=WEEKLY", ny(2024, 1, 3, 18, 30), 2), vec![ny(2024, 1, 10, 18, 30), ny(2024, 1, 17, 18, 30)]);
+        // Every other week on Monday and Wednesday, from a Monday
+        assert_eq!(
+            occurrences("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE", ny(2024, 1, 1, 8, 0), 4),
+            vec![ny(2024, 1, 3, 8, 0), ny(2024, 1, 15, 8, 0), ny(2024, 1, 17, 8, 0), ny(2024, 1, 29, 8, 0)]
+        );
+    }
+
+    #[test]
+    fn by_weekday() {
+        // Weekdays only: Friday goes on to Monday
+        assert_eq!(
+            occurrences("FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR", ny(2024, 1, 4, 7, 0), 3),
+            vec![ny(2024, 1, 5, 7, 0), ny(2024, 1, 8, 7, 0), ny(2024, 1, 9, 7, 0)]
+        );
+        // A weekly rule may start on a day it doesn't list
+        assert_eq!(occurrences("FREQ=WEEKLY;BYDAY=TU,TH", ny(2024, 1, 6, 12, 0), 2), vec![ny(2024, 1, 9, 12, 0), ny(2024, 1, 11, 12, 0)]);
+    }
+
+    #[test]
+    fn monthly() {
+        assert_eq!(occurrences("FREQ=MONTHLY", ny(2024, 1, 15, 10, 0), 2), vec![ny(2024, 2, 15, 10, 0), ny(2024, 3, 15, 10, 0)]);
+        // Months without a 31st are skipped
+        assert_eq!(occurrences("FREQ=MONTHLY", ny(2024, 1, 31, 10, 0), 2), vec![ny(2024, 3, 31, 10, 0), ny(2024, 5, 31, 10, 0)]);
+        assert_eq!(occurrences("FREQ=MONTHLY;BYMONTHDAY=-1", ny(2024, 1, 31, 10, 0), 2), vec![ny(2024, 2, 29, 10, 0), ny(2024, 3, 31, 10, 0)]);
+        assert_eq!(occurrences("FREQ=MONTHLY;BYDAY=2TU", ny(2024, 1, 9, 19, 0), 2), vec![ny(2024, 2, 13, 19, 0), ny(2024, 3, 12, 19, 0)]);
+        assert_eq!(occurrences("FREQ=MONTHLY;BYDAY=-1FR", ny(2024, 1, 26, 17, 0), 2), vec![ny(2024, 2, 23, 17, 0), ny(2024, 3, 29, 17, 0)]);
+        // Friday the 13th
+        assert_eq!(occurrences("FREQ=MONTHLY;BYDAY=FR;BYMONTHDAY=13", ny(2024, 1, 1, 0, 0), 2), vec![ny(2024, 9, 13, 0, 0), ny(2024, 12, 13, 0, 0)]);
+    }
+
+    #[test]
+    fn keeps_the_local_time_across_dst() {
+        // Clocks spring forward on 2024-03-10 and fall back on 2024-11-03
+        let spring = occurrences("FREQ=DAILY", ny(2024, 3, 9, 9, 0), 1)[0];
+        assert_eq!(spring, ny(2024, 3, 10, 9, 0));
+        assert_eq!(spring - ny(2024, 3, 9, 9, 0), chrono::TimeDelta::hours(23));
+        let fall = occurrences("FREQ=WEEKLY", ny(2024, 10, 27, 9, 0), 1)[0];
+        assert_eq!(fall - ny(2024, 10, 27, 9, 0), chrono::TimeDelta::hours(7 * 24 + 1));
+    }
+
+    #[test]
+    fn skipped_and_repeated_local_times() {
+        // 2:30 doesn't exist on 2024-03-10, so the gap's hour is added
+        assert_eq!(occurrences("FREQ=DAILY", ny(2024, 3, 9, 2, 30), 1), vec![ny(2024, 3, 10, 3, 30)]);
+        // 1:30 happens twice on 2024-11-03; the first one counts
+        let twice = occurrences("FREQ=DAILY", ny(2024, 11, 2, 1, 30), 1)[0];
+        assert_eq!(twice, Utc.with_ymd_and_hms(2024, 11, 3, 5, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn until_and_count() {
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103", ny(2024, 1, 1, 9, 0), 5).len(), 2);
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240102T140000Z", ny(2024, 1, 1, 9, 0), 5), vec![ny(2024, 1, 2, 9, 0)]);
+        // COUNT includes the occurrence being completed
+        assert_eq!(occurrences("FREQ=WEEKLY;COUNT=3", ny(2024, 1, 1, 9, 0), 5).len(), 2);
+    }
+
+    #[test]
+    fn upcoming_skips_missed_occurrences() {
+        let rule = Rule::parse("FREQ=DAILY;COUNT=10").unwrap();
+        let (at, rest) = rule.upcoming(ny(2024, 1, 1, 9, 0), ny(2024, 1, 4, 12, 0), &New_York).unwrap();
+        assert_eq!(at, ny(2024, 1, 5, 9, 0));
+        assert_eq!(rest.count, Some(6));
+    }
+
+    #[test]
+    fn parses_and_prints() {
+        let rule = Rule::parse("RRULE:freq=monthly;interval=1;byday=-1fr,2TU;COUNT=4").unwrap();
+        assert_eq!(rule.to_string(), "FREQ=MONTHLY;BYDAY=-1FR,2TU;COUNT=4");
+        assert_eq!(Rule::parse(&rule.to_string()), Ok(rule));
+        for invalid in ["", "FREQ=HOURLY", "FREQ=DAILY;INTERVAL=0", "FREQ=WEEKLY;BYDAY=2TU", "FREQ=DAILY;COUNT=2;UNTIL=20240101", "FREQ=DAILY;BYHOUR=9", "FREQ=MONTHLY;BYDAY=XX"] {
+            assert!(Rule::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(Repeat::parse(" "), Ok(Repeat::Clear));
+    }
+}
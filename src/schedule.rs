@@ -0,0 +1,227 @@
+// Due dates and reminders: reading the dates people type, the overdue/today/upcoming
+// views, and the background task that announces reminders as they come due.
+// Dates without a zone are in the server's local time.
+use crate::broadcast::Rooms;
+use crate::db::take_due_reminders;
+use crate::protocol::ServerMessage;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::tokio::time::interval;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// A due date or reminder field of a create or edit: left out, cleared, or set
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum When {
+    #[default]
+    Unchanged,
+    Clear,
+    At(DateTime<Utc>),
+}
+
+impl When {
+    // Blank clears. Takes RFC 3339, `YYYY-MM-DDTHH:MM` (what datetime-local
+    // inputs send), `YYYY-MM-DD` for the end of that day, `today` and `tomorrow`.
+    pub fn parse(input: &str) -> Result<When, String> {
+        When::parse_in(input, Utc::now(), &Local)
+    }
+
+    // Like parse, with `today` meaning the date in `tz` at `now`, and times
+    // without a zone read in `tz`
+    pub fn parse_in<Tz: TimeZone>(input: &str, now: DateTime<Utc>, tz: &Tz) -> Result<When, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(When::Clear);
+        }
+
+        let today = now.with_timezone(tz).date_naive();
+        let at = match input.to_ascii_lowercase().as_str() {
+            "today" => Some(end_of_day(tz, today)),
+            "tomorrow" => today.succ_opt().map(|date| end_of_day(tz, date)),
+            _ => DateTime::parse_from_rfc3339(input)
+                .map(|at| at.with_timezone(&Utc))
+                .ok()
+                .or_else(|| {
+                    ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S"]
+                        .iter()
+                        .find_map(|format| NaiveDateTime::parse_from_str(input, format).ok())
+                        .and_then(|at| local(tz, at))
+                })
+                .or_else(|| NaiveDate::parse_from_str(input, "%Y-%m-%d").ok().map(|date| end_of_day(tz, date))),
+        };
+        at.map(When::At)
+            .ok_or_else(|| format!("Can't read {:?} as a date; use YYYY-MM-DD or YYYY-MM-DDTHH:MM", input))
+    }
+
+    // The value after applying this change to `current`
+    pub fn apply(self, current: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+        match self {
+            When::Unchanged => current,
+            When::Clear => None,
+            When::At(at) => Some(at),
+        }
+    }
+}
+
+impl<'v> FromFormField<'v> for When {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        When::parse(field.value).map_err(|message| form::Error::validation(message).into())
+    }
+
+    // A form without the field leaves the todo's date alone
+    fn default() -> Option<Self> {
+        Some(When::Unchanged)
+    }
+}
+
+// None for times that don't exist in `tz`, like those skipped by a DST change
+fn local<Tz: TimeZone>(tz: &Tz, at: NaiveDateTime) -> Option<DateTime<Utc>> {
+    tz.from_local_datetime(&at).earliest().map(|at| at.with_timezone(&Utc))
+}
+
+fn start_of_day<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    // Where DST starts at midnight, the day starts at the first time that exists
+    local(tz, midnight)
+        .or_else(|| local(tz, midnight + TimeDelta::hours(1)))
+        .unwrap_or_else(|| midnight.and_utc())
+}
+
+// A date without a time is due by the end of that day
+fn end_of_day<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    match date.succ_opt() {
+        Some(next) => start_of_day(tz, next) - TimeDelta::seconds(1),
+        None => start_of_day(tz, date),
+    }
+}
+
+// Views of a list by due date
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, FromFormField, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Due {
+    // Past due and not done yet
+    Overdue,
+    // Due some time today
+    Today,
+    // Due after today
+    Upcoming,
+}
+
+impl Due {
+    // The range of due_at (as Unix seconds, end exclusive) this view shows, and
+    // whether it leaves out completed todos. Days are those of `tz`.
+    pub fn window<Tz: TimeZone>(self, now: DateTime<Utc>, tz: &Tz) -> (i64, i64, bool) {
+        let today = now.with_timezone(tz).date_naive();
+        let tomorrow = start_of_day(tz, today.succ_opt().unwrap_or(today));
+        match self {
+            Due::Overdue => (i64::MIN, now.timestamp(), true),
+            Due::Today => (start_of_day(tz, today).timestamp(), tomorrow.timestamp(), false),
+            Due::Upcoming => (tomorrow.timestamp(), i64::MAX, false),
+        }
+    }
+}
+
+// Announce each reminder once its time has come, on the room of the todo's list.
+// Runs for the life of the server.
+pub async fn send_reminders(pool: SqlitePool, rooms: Rooms, period: Duration) {
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        let todos = match take_due_reminders(&pool, Utc::now()).await {
+            Ok(todos) => todos,
+            Err(e) => {
+                println!("Failed to check reminders: {:?}", e);
+                continue;
+            }
+        };
+
+        for todo in todos {
+            println!("⏰ Reminder for todo {}: {}", todo.id, todo.title);
+            let reminder = ServerMessage::Reminder {
+                todo_id: todo.id,
+                title: todo.title,
+                due_at: todo.due_at,
+            };
+            rooms.get(todo.list_id).publish(reminder, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{end_of_day, start_of_day, Due, When};
+    use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+    use chrono_tz::America::{New_York, Sao_Paulo};
+    use chrono_tz::Tz;
+
+    fn utc(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    // What a New York user typing `input` at `now` means
+    fn ny(input: &str, now: &str) -> Result<When, String> {
+        When::parse_in(input, utc(now), &New_York)
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn reads_zoned_and_local_times() {
+        let now = "2024-06-01T12:00:00Z";
+        assert_eq!(ny("2024-06-03T09:30:00+02:00", now), Ok(When::At(utc("2024-06-03T07:30:00Z"))));
+        // datetime-local inputs and hand-typed times are New York time, daylight or not
+        assert_eq!(ny("2024-06-03T09:30", now), Ok(When::At(utc("2024-06-03T13:30:00Z"))));
+        assert_eq!(ny(" 2024-12-03 09:30:15 ", now), Ok(When::At(utc("2024-12-03T14:30:15Z"))));
+        // A date alone is due by the end of it
+        assert_eq!(ny("2024-06-03", now), Ok(When::At(utc("2024-06-04T03:59:59Z"))));
+    }
+
+    #[test]
+    fn today_is_the_users_today() {
+        // Already the 2nd in UTC, still the 1st in New York
+        let now = "2024-06-02T02:00:00Z";
+        assert_eq!(ny("Today", now), Ok(When::At(utc("2024-06-02T03:59:59Z"))));
+        assert_eq!(ny("tomorrow", now), Ok(When::At(utc("2024-06-03T03:59:59Z"))));
+    }
+
+    #[test]
+    fn blank_clears_and_nonsense_fails() {
+        let now = "2024-06-01T12:00:00Z";
+        assert_eq!(ny("  ", now), Ok(When::Clear));
+        assert!(ny("next week", now).is_err());
+        assert!(ny("2024-02-30", now).is_err());
+        // Clocks jump from 2:00 to 3:00 that night
+        assert!(ny("2024-03-10T02:30", now).is_err());
+        assert_eq!(ny("2024-03-10T03:30", now), Ok(When::At(utc("2024-03-10T07:30:00Z"))));
+    }
+
+    #[test]
+    fn days_start_at_the_first_time_that_exists() {
+        // São Paulo skipped from midnight to 1:00 when DST began in 2018
+        assert_eq!(start_of_day(&Sao_Paulo, date(2018, 11, 4)), utc("2018-11-04T01:00:00-02:00"));
+        assert_eq!(end_of_day(&Sao_Paulo, date(2018, 11, 3)), utc("2018-11-03T23:59:59-03:00"));
+        assert_eq!(start_of_day(&New_York, date(2024, 3, 10)), utc("2024-03-10T00:00:00-05:00"));
+    }
+
+    #[test]
+    fn due_views_follow_local_days() {
+        let window = |due: Due, now: &str, tz: &Tz| due.window(utc(now), tz);
+        let at = |rfc3339: &str| utc(rfc3339).timestamp();
+
+        let now = "2024-03-10T15:00:00Z";
+        assert_eq!(window(Due::Overdue, now, &New_York), (i64::MIN, at(now), true));
+        // The day clocks spring forward is 23 hours long
+        let (start, end, open_only) = window(Due::Today, now, &New_York);
+        assert_eq!((start, end, open_only), (at("2024-03-10T05:00:00Z"), at("2024-03-11T04:00:00Z"), false));
+        assert_eq!(end - start, 23 * 3600);
+        assert_eq!(window(Due::Upcoming, now, &New_York), (end, i64::MAX, false));
+
+        // Late evening in New York is already tomorrow in UTC
+        let (start, _, _) = window(Due::Today, "2024-06-02T02:00:00Z", &New_York);
+        assert_eq!(start, New_York.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap().timestamp());
+    }
+}
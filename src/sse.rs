@@ -0,0 +1,252 @@
+// Server-Sent Events fallback for networks that block WebSocket upgrades. The
+// stream carries the same JSON payloads as /todo-ws, and client messages come
+// back as plain POSTs naming the stream's connection.
+use crate::broadcast::Rooms;
+use crate::crdt::TitleDocs;
+use crate::db::{get_todos, DbError, TodoQuery, User};
+use crate::live::Live;
+use crate::presence::{ClientSessions, Presence, SessionGuard};
+use crate::protocol::{ClientMessage, ErrorCode, ServerMessage, TodoUpdate};
+use crate::{handle_client_message, resolve_list, resume, SocketContext, CURSOR_INTERVAL};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::Custom;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{Json, Value};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::time::{sleep_until, Instant};
+use rocket::{Request, Route, State};
+use sqlx::SqlitePool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+pub fn routes() -> Vec<Route> {
+    routes![get_todo_events, post_todo_events]
+}
+
+// Where a stream should pick up from: the `Last-Event-ID` a reconnecting
+// EventSource sends, or `last_event_id` in the query for the first connection
+pub struct LastEventId(pub Option<(u32, u64)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = req
+            .headers()
+            .get_one("Last-Event-ID")
+            .or_else(|| req.query_value::<&str>("last_event_id").and_then(Result::ok));
+
+        // Ids are `epoch:seq`; anything else just means starting from the snapshot
+        let position = id
+            .and_then(|id| id.split_once(':'))
+            .and_then(|(epoch, seq)| Some((epoch.parse().ok()?, seq.parse().ok()?)));
+        Outcome::Success(LastEventId(position))
+    }
+}
+
+// Broadcasts carry their position as the event id, so the browser resumes from it
+fn to_event(message: &Value, epoch: u32) -> Event {
+    let event = Event::json(message);
+    // Init reports the current seq, but the page may still be missing events before it
+    if message["event"] == "init" {
+        return event;
+    }
+    match message["seq"].as_u64() {
+        Some(seq) => event.id(format!("{}:{}", epoch, seq)),
+        None => event,
+    }
+}
+
+#[utoipa::path(
+    tag = "events",
+    params(
+        ("list" = Option<String>, Query, description = "List slug; the default list when left out"),
+        ("Last-Event-ID" = Option<String>, Header, description = "`epoch:seq` of the last event seen, to replay what was missed"),
+        ("last_event_id" = Option<String>, Query, description = "Same as Last-Event-ID, for the first connection"),
+    ),
+    responses(
+        (status = 200, description = "`init`, then the same messages as /todo-ws (see /api/asyncapi.json). When resuming, `init` has no `todos` and the replay or `resync` follows it.", content_type = "text/event-stream", body = TodoUpdate),
+        (status = 404, description = "No such list"),
+    ),
+    security(("session" = []))
+)]
+#[get("/todo-events?<list>")]
+async fn get_todo_events<'r>(user: User, rooms: &'r State<Rooms>, sessions: &'r State<ClientSessions>, pool: &'r State<SqlitePool>, titles: &'r State<TitleDocs>, last_event_id: LastEventId, list: Option<&str>) -> Result<EventStream![Event + 'r], DbError> {
+    let list = resolve_list(pool, list).await?;
+    let list_id = list.id;
+    let queue = rooms.get(list_id);
+    let connection_id = format!("sse_client_{}", rand::random::<u64>());
+    let mut rx = queue.subscribe();
+
+    let events = EventStream! {
+        let queue = &*queue;
+        let count = sessions.add_client(list_id, Presence::new(connection_id.clone(), &user));
+        println!("New event stream: {} for {} on list {}. Connected users: {} ({} connections)", connection_id, user.source_id(), list.slug, count.people, count.connections);
+
+        // Dropped along with the stream once the client goes away
+        let _session = SessionGuard {
+            sessions,
+            titles,
+            queue,
+            list_id,
+            ws_client_id: connection_id.clone(),
+        };
+
+        let stream = sessions.open_stream(&connection_id, queue.current_seq(), CURSOR_INTERVAL);
+        queue.publish(ServerMessage::UserCount { connected_users: count.people, connections: count.connections }, None);
+
+        // A resuming client gets the replay or resync instead of a snapshot, which would
+        // already hold the events replayed after it
+        let todos = match last_event_id.0 {
+            Some(_) => None,
+            None => match get_todos(pool, list_id, &TodoQuery::default()).await {
+                Ok(todos) => Some(todos),
+                Err(e) => {
+                    // An empty init would tell the client its list is empty; it reconnects instead
+                    yield Event::json(&ServerMessage::Error { code: ErrorCode::Failed, message: format!("Could not load todos: {}", e) });
+                    return;
+                }
+            },
+        };
+        let init = ServerMessage::Init {
+            connection_id: connection_id.clone(),
+            todos,
+            connected_users: count.people,
+            connections: count.connections,
+            presence: sessions.snapshot(list_id),
+            seq: stream.last_sent_seq.load(Ordering::SeqCst),
+            epoch: queue.epoch(),
+        };
+        yield Event::json(&init);
+
+        // Same replay or resync a WebSocket gets for a `resume` message
+        if let Some((epoch, seq)) = last_event_id.0 {
+            let mut missed = Vec::new();
+            let _ = resume(&mut missed, queue, pool, list_id, epoch, seq).await;
+            for message in &missed {
+                stream.last_sent_seq.fetch_max(message["seq"].as_u64().unwrap_or(0), Ordering::SeqCst);
+                yield to_event(message, queue.epoch());
+            }
+        }
+
+        loop {
+            // A POST in progress has the throttle, and wakes us if it leaves a cursor held
+            let deadline = stream.cursor.try_lock().ok().and_then(|cursor| cursor.deadline());
+            let received = rocket::tokio::select! {
+                received = rx.recv() => received,
+                _ = stream.cursor_held.notified() => continue,
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let Some(position) = stream.cursor.lock().await.take_due() {
+                        queue.publish(position, Some(user.source_id()));
+                    }
+                    continue;
+                }
+            };
+            match received {
+                Ok(update) => {
+                    // Already delivered by the snapshot, a replay or a POST's replies
+                    if stream.last_sent_seq.fetch_max(update.seq, Ordering::SeqCst) >= update.seq {
+                        continue;
+                    }
+                    match serde_json::to_value(&update) {
+                        Ok(message) => yield to_event(&message, queue.epoch()),
+                        Err(e) => println!("Failed to serialize event: {}", e),
+                    }
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("Event stream {} lagged by {} messages, resyncing", connection_id, skipped);
+                    let mut missed = Vec::new();
+                    let last_sent_seq = stream.last_sent_seq.load(Ordering::SeqCst);
+                    let _ = resume(&mut missed, queue, pool, list_id, queue.epoch(), last_sent_seq).await;
+                    for message in &missed {
+                        stream.last_sent_seq.fetch_max(message["seq"].as_u64().unwrap_or(0), Ordering::SeqCst);
+                        yield to_event(message, queue.epoch());
+                    }
+                },
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    // Comment lines keep proxies from timing out a quiet stream; a failed write ends it
+    Ok(events.heartbeat(sessions.heartbeat().ping_interval))
+}
+
+// A client message for an event stream's connection, with the same JSON body as
+// a WebSocket frame. Replies come back in the response body instead of a socket.
+#[utoipa::path(
+    tag = "events",
+    params(("connection" = String, Query, description = "`connection_id` from the stream's `init`")),
+    request_body = ClientMessage,
+    responses(
+        (status = 200, description = "Replies the socket would have sent", body = Vec<ServerMessage>),
+        (status = 400, description = "Malformed or invalid message", body = Vec<ServerMessage>),
+        (status = 404, description = "No event stream with that id for this user, or no such todo in its list", body = Vec<ServerMessage>),
+        (status = 409, description = "A save based on a stale title", body = Vec<ServerMessage>),
+        (status = 423, description = "Someone else holds the todo's edit lock", body = Vec<ServerMessage>),
+        (status = 500, description = "The server failed to apply the message", body = Vec<ServerMessage>),
+    ),
+    security(("session" = []))
+)]
+#[post("/todo-events?<connection>", data = "<body>")]
+async fn post_todo_events(user: User, connection: &str, body: String, sessions: &State<ClientSessions>, live: Live<'_>, pool: &State<SqlitePool>, titles: &State<TitleDocs>) -> Custom<Json<Vec<Value>>> {
+    let source_id = user.source_id();
+    let not_found = || {
+        let error = ServerMessage::Error {
+            code: ErrorCode::NotFound,
+            message: format!("No event stream {} for this user", connection),
+        };
+        Custom(Status::NotFound, Json(vec![serde_json::to_value(error).unwrap_or_default()]))
+    };
+    let found = sessions.find(connection).filter(|(_, presence)| presence.source_id == source_id);
+    let Some(((list_id, _), stream)) = found.zip(sessions.stream(connection)) else {
+        return not_found();
+    };
+
+    let msg = match ClientMessage::parse(&body) {
+        Ok(msg) => msg,
+        Err(error) => return Custom(Status::BadRequest, Json(vec![serde_json::to_value(error).unwrap_or_default()])),
+    };
+
+    let queue = live.room(list_id);
+    let socket = SocketContext {
+        connection_id: connection,
+        source_id: &source_id,
+        list_id,
+        queue: &queue,
+        live: &live,
+        pool,
+        titles,
+        sessions,
+    };
+    // The connection's throttle and seq carry over between requests; a cursor held
+    // back here goes out on the stream once it's due
+    let mut cursor = stream.cursor.lock().await;
+    // The stream may have ended while this waited its turn
+    if !sessions.stream(connection).is_some_and(|current| Arc::ptr_eq(&current, &stream)) {
+        return not_found();
+    }
+    let mut last_sent_seq = stream.last_sent_seq.load(Ordering::SeqCst);
+    let mut replies = Vec::new();
+    let _ = handle_client_message(msg, &mut replies, &socket, &mut last_sent_seq, &mut cursor).await;
+    stream.last_sent_seq.fetch_max(last_sent_seq, Ordering::SeqCst);
+    let held = cursor.deadline().is_some();
+    drop(cursor);
+    if held {
+        stream.cursor_held.notify_one();
+    }
+
+    let status = match replies.iter().find_map(|reply| reply["event"].as_str().map(|event| (event, reply))) {
+        Some(("error", reply)) => match serde_json::from_value(reply["code"].clone()) {
+            Ok(ErrorCode::Malformed | ErrorCode::Invalid | ErrorCode::Unsupported) => Status::BadRequest,
+            Ok(ErrorCode::NotFound) => Status::NotFound,
+            Ok(ErrorCode::Failed) | Err(_) => Status::InternalServerError,
+        },
+        Some(("conflict", _)) => Status::Conflict,
+        Some(("locked", _)) => Status::Locked,
+        _ => Status::Ok,
+    };
+    Custom(status, Json(replies))
+}